@@ -1,36 +1,37 @@
-extern crate bincode;
 #[macro_use]
 extern crate serde_derive;
 extern crate rpsrtsrs;
 extern crate docopt;
 
-use std::net::TcpStream;
 use std::ops::Deref;
-use std::io::Write;
-use std::{thread, time};
+use std::process;
+use std::time::Instant;
 
-use rpsrtsrs::state::GameState;
-use rpsrtsrs::network::{Command, Message};
+use rpsrtsrs::network::{Command, Message, SessionToken, ProtocolVersion, PROTOCOL_VERSION, millis};
+use rpsrtsrs::transport::{self, Transport};
 
 use docopt::Docopt;
 
-use bincode::{serialize_into, deserialize_from, Infinite};
-use bincode::internal::Result;
-
 static USAGE: &'static str = "
-Usage: cli_client [-p PORT] [-i IP] [-r ID] (read|move <id> <x> <y>)
+Usage: cli_client [-p PORT] [-i IP] [-u] [-r ID -t TOKEN] [--ready] (read|move <id> <x> <y>)
 
 Options:
-    -p PORT  The port to connect to [default: 8080].
-    -i IP    The ipv4 address to connect to [default: 127.0.0.1].
-    -r ID    Reconnect with the given ID
+    -p PORT   The port to connect to [default: 8080].
+    -i IP     The ipv4 address to connect to [default: 127.0.0.1].
+    -u        Connect over UDP instead of TCP.
+    -r ID     Reconnect with the given ID
+    -t TOKEN  The session token for reconnecting
+    --ready   Tell the lobby that the player is ready to start the match
 ";
 
 #[derive(Deserialize, Debug)]
 struct Args {
     flag_p: u16,
     flag_i: String,
+    flag_u: bool,
     flag_r: Option<u32>,
+    flag_t: Option<String>,
+    flag_ready: bool,
 
     cmd_read: bool,
     arg_id: Option<u32>,
@@ -49,28 +50,62 @@ fn main() {
 
     println!("connecting to host: {:?}:{:?} reconnect? {:?}", host, port, reconnect);
 
-    let mut stream = TcpStream::connect((host.deref(), port)).unwrap();
+    let transport = if args.flag_u { Transport::Udp } else { Transport::Tcp };
+    let mut stream = transport::connect(transport, (host.deref(), port)).unwrap();
+    // Ask for full snapshots only, so that they can be printed as they are
+    let version = ProtocolVersion { version: PROTOCOL_VERSION, features: 0 };
 
     match reconnect {
         Some(id) => {
-            serialize_into(&mut stream,
-                           &Message::ClientReconnect(id.into()),
-                           Infinite)
-                .unwrap();
+            let token: SessionToken = args.flag_t.expect("-t TOKEN missing").parse()
+                .unwrap_or_else(|e| panic!("{}", e));
+            stream.send(&Message::ClientReconnect(version, id.into(), token)).unwrap();
         }
         None => {
-            serialize_into(&mut stream, &Message::ClientHello, Infinite).unwrap();
+            stream.send(&Message::ClientHello(version)).unwrap();
         }
     }
-    let server_hello: Result<Message> = deserialize_from(&mut stream, Infinite);
+    let server_hello = stream.receive();
     println!("{:?}", server_hello);
+    match server_hello {
+        Ok(Message::ServerHello(_, id, token, _)) => {
+            println!("Reconnect with: -r {} -t {}", id, token);
+        }
+        Ok(Message::VersionMismatch(server_version)) => {
+            println!("Incompatible server: it speaks protocol version {}, we speak {}",
+                     server_version, version);
+            process::exit(1);
+        }
+        Ok(Message::Error(code, description)) => {
+            println!("{}: {}", code, description);
+            process::exit(1);
+        }
+        _ => {}
+    }
+
+    if args.flag_ready {
+        stream.send(&Message::Ready(true)).unwrap();
+    }
+
+    // The timestamps of pings are relative to this
+    let epoch = Instant::now();
 
     if cmd_read {
+        stream.send(&Message::Ping(millis(epoch.elapsed()))).unwrap();
         loop {
-            let game_state: Result<GameState> = deserialize_from(&mut stream,
-                                                                 Infinite);
-            match game_state {
-                Ok(game) => println!("{:?}", game),
+            match stream.receive() {
+                Ok(Message::UpdateGamestate(tick, game)) => println!("{}: {:?}", tick, game),
+                Ok(Message::Ping(timestamp)) => {
+                    stream.send(&Message::Pong(timestamp)).unwrap();
+                    // Measure the round trip time as often as the server
+                    stream.send(&Message::Ping(millis(epoch.elapsed()))).unwrap();
+                }
+                Ok(Message::Pong(timestamp)) => println!("RTT: {} ms", millis(epoch.elapsed()) - timestamp),
+                Ok(Message::GameOver(results)) => {
+                    println!("Game over: {:?}", results);
+                    return;
+                }
+                Ok(message) => println!("{:?}", message),
                 Err(e) => {
                     println!("{:?}", e);
                     return;
@@ -81,12 +116,25 @@ fn main() {
         let id = args.arg_id.expect("<id> missing");
         let x = args.arg_x.expect("<x> missing");
         let y = args.arg_y.expect("<y> missing");
-        serialize_into(&mut stream,
-                       &Message::Command(Command::Move(id.into(), [x, y])),
-                       Infinite)
-            .unwrap();
-        stream.flush().unwrap();
+        stream.send(&Message::Command(1, Command::Move(id.into(), [x, y]))).unwrap();
+
+        // The server answers the ping after it processed the command
+        stream.send(&Message::Ping(millis(epoch.elapsed()))).unwrap();
+        loop {
+            match stream.receive() {
+                Ok(Message::Pong(timestamp)) => {
+                    println!("RTT: {} ms", millis(epoch.elapsed()) - timestamp);
+                    break;
+                }
+                Ok(Message::UpdateGamestate(..)) | Ok(Message::Ping(_)) => {}
+                Ok(message) => println!("{:?}", message),
+                Err(e) => {
+                    println!("{:?}", e);
+                    break;
+                }
+            }
+        }
     }
 
-    thread::sleep(time::Duration::from_millis(100));
+    stream.shutdown();
 }
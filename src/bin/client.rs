@@ -1,3 +1,6 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate docopt;
 extern crate piston;
 extern crate graphics;
 extern crate opengl_graphics;
@@ -20,10 +23,46 @@ use piston::event_loop::*;
 #[cfg(feature = "include_glfw")] use glfw_window::GlfwWindow as Window;
 #[cfg(feature = "include_glutin")] use glutin_window::GlutinWindow as Window;
 
+use docopt::Docopt;
+
 use rpsrtsrs::client::*;
+use rpsrtsrs::client::recent::{self, Address, RecentServers};
+use rpsrtsrs::transport::Transport;
+use rpsrtsrs::simulator::Conditions;
+
+static USAGE: &'static str = "
+Usage: client [-p PORT] [-i IP] [-d DELAY] [-u] [options]
+
+Options:
+    -p PORT   The port to connect to [default: 8080].
+    -i IP     The ipv4 address to connect to [default: 127.0.0.1].
+    -d DELAY  Time in ms the rendered game lags behind the server [default: 100].
+    -u        Connect over UDP instead of TCP.
+
+Network simulation options:
+    --latency MS    Simulate a network with the given latency in ms [default: 0].
+    --jitter MS     Simulate a random variation of the latency up to MS [default: 0].
+    --loss P        Simulate the loss of frames with probability P [default: 0].
+    --duplicate P   Simulate duplicated frames with probability P [default: 0].
+    --reorder P     Simulate reordered frames with probability P [default: 0].
+";
 
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_p: u16,
+    flag_i: String,
+    flag_d: f64,
+    flag_u: bool,
+    flag_latency: u64,
+    flag_jitter: u64,
+    flag_loss: f64,
+    flag_duplicate: f64,
+    flag_reorder: f64,
+}
 
 fn main() {
+    let args: Args = Docopt::new(USAGE).and_then(|d| d.deserialize())
+                                       .unwrap_or_else(|e| e.exit());
     let opengl = OpenGL::V3_2;
 
     // Create an Glutin window.
@@ -39,6 +78,19 @@ fn main() {
 
     // Create a new game and run it.
     let mut app = App::new(GlGraphics::new(opengl));
+    app.set_server(Address { host: args.flag_i, port: args.flag_p });
+    app.set_recent_servers(RecentServers::load(recent::default_path()));
+    app.set_render_delay(args.flag_d);
+    if args.flag_u {
+        app.set_transport(Transport::Udp);
+    }
+    app.set_network_conditions(Conditions {
+        latency_ms: args.flag_latency,
+        jitter_ms: args.flag_jitter,
+        loss: args.flag_loss,
+        duplication: args.flag_duplicate,
+        reordering: args.flag_reorder,
+    });
 
     let mut events = Events::new(EventSettings::new());
     while let Some(e) = events.next(&mut window) {
@@ -53,6 +105,10 @@ fn main() {
             }
         }
 
+        if let Some(text) = e.text_args() {
+            app.on_text(&text);
+        }
+
         if let Some(args) = e.mouse_cursor_args() {
             app.on_mouse_move(args);
         }
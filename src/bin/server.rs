@@ -8,21 +8,53 @@ use std::ops::Deref;
 
 use docopt::Docopt;
 
-use rpsrtsrs::server::Server;
+use rpsrtsrs::server::{Server, MatchEnd, AbandonedUnits};
+use rpsrtsrs::settings::GameSettings;
+use rpsrtsrs::transport::Transport;
+use rpsrtsrs::simulator::Conditions;
 
 static USAGE: &'static str = "
-Usage: server [-p PORT] [-i IP]
+Usage: server [-p PORT] [-i IP] [-m MAX] [-g SECS] [-a UNITS] [-n NAME] [-c FILE] [-s] [-u] [options]
 
 Options:
     -p PORT  The port to listen on [default: 8080].
     -i IP    The ipv4 address to listen on [default: 127.0.0.1].
+    -m MAX   The maximum number of players [default: 8].
+    -g SECS  Time disconnected players have to reconnect [default: 30].
+    -a UNITS  What happens to the units of players who did not reconnect in time:
+              remove, ai or freeze [default: freeze].
+    -n NAME  Announce the server in the local network under the given name,
+             together with -i 0.0.0.0 for accepting clients from other hosts.
+    -c FILE  Load the settings of the matches from a TOML file,
+             see docs/settings.toml.
+    -s       Shut down when the match is over instead of waiting for the next one.
+    -u       Accept clients over UDP instead of TCP.
     -r ID    Reconnect with the given ID
+
+Network simulation options:
+    --latency MS    Simulate a network with the given latency in ms [default: 0].
+    --jitter MS     Simulate a random variation of the latency up to MS [default: 0].
+    --loss P        Simulate the loss of frames with probability P [default: 0].
+    --duplicate P   Simulate duplicated frames with probability P [default: 0].
+    --reorder P     Simulate reordered frames with probability P [default: 0].
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_p: u16,
     flag_i: String,
+    flag_m: usize,
+    flag_g: u64,
+    flag_a: String,
+    flag_n: Option<String>,
+    flag_c: Option<String>,
+    flag_s: bool,
+    flag_u: bool,
+    flag_latency: u64,
+    flag_jitter: u64,
+    flag_loss: f64,
+    flag_duplicate: f64,
+    flag_reorder: f64,
 }
 
 fn main() {
@@ -31,6 +63,31 @@ fn main() {
     let host = args.flag_i;
     let port = args.flag_p;
 
-    let server = Server::new((host.deref(), port), (800.0, 600.0)).expect("Could not initialize server");
+    let settings = match args.flag_c {
+        Some(path) => GameSettings::load(&path)
+            .unwrap_or_else(|e| panic!("Could not load the settings from {}: {}", path, e)),
+        None => GameSettings::default(),
+    };
+    let mut server = Server::new((host.deref(), port), settings).expect("Could not initialize server");
+    server.set_max_players(args.flag_m);
+    server.set_grace_period(args.flag_g * 1000);
+    let abandoned_units: AbandonedUnits = args.flag_a.parse().unwrap_or_else(|e| panic!("{}", e));
+    server.set_abandoned_units(abandoned_units);
+    if let Some(name) = args.flag_n {
+        server.set_name(name);
+    }
+    if args.flag_s {
+        server.set_match_end(MatchEnd::Shutdown);
+    }
+    if args.flag_u {
+        server.set_transport(Transport::Udp);
+    }
+    server.set_network_conditions(Conditions {
+        latency_ms: args.flag_latency,
+        jitter_ms: args.flag_jitter,
+        loss: args.flag_loss,
+        duplication: args.flag_duplicate,
+        reordering: args.flag_reorder,
+    });
     server.serve();
 }
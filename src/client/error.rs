@@ -1,28 +1,63 @@
+use std::error::Error;
+use std::fmt;
+
 use opengl_graphics::GlGraphics;
 use opengl_graphics::glyph_cache::GlyphCache;
 
 use piston::input::{RenderArgs};
 
-use colors::{BLACK, YELLOW};
+use colors::{BLACK, YELLOW, ORANGE};
+use network::ErrorCode;
+
+/// An error reported by the server with a `Message::Error`.
+#[derive(Clone, Debug)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub description: String,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}: {}", self.code, self.description)
+    }
+}
+
+impl Error for ServerError {
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Message {
     message: String,
+    details: String,
 }
 
 impl Message {
     pub fn new(message: String) -> Message {
-        Message { message: message }
+        Message { message: message, details: String::new() }
+    }
+
+    /// Show the reason code of errors reported by the server as the message
+    /// and their description below.
+    pub fn from_error(error: &(Error + 'static)) -> Message {
+        match error.downcast_ref::<ServerError>() {
+            Some(e) => Message { message: e.code.to_string(), details: e.description.clone() },
+            None => Message::new(error.to_string()),
+        }
     }
 
     pub fn render(&self, args: &RenderArgs, gl: &mut GlGraphics, cache: &mut GlyphCache) {
         use graphics::{Text, clear, Transformed};
         let text = Text::new_color(YELLOW, 64);
+        let details = Text::new_color(ORANGE, 24);
         gl.draw(args.viewport(), |c, gl| {
             // Clear the screen.
             clear(BLACK, gl);
             let transform = c.transform.trans(0.0, 100.0);
             text.draw(&self.message, cache, &c.draw_state, transform, gl);
+            details.draw(&self.details, cache, &c.draw_state, transform.trans(0.0, 50.0), gl);
         });
     }
 }
@@ -0,0 +1,196 @@
+//! Smooth rendering of the snapshots received from the server.
+//!
+//! The client renders the game slightly in the past, so that there usually is
+//! a snapshot before and after the rendered point in time. Units are placed
+//! between the two, instead of jumping whenever a snapshot arrives.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use network::TICK_MS;
+use state::GameState;
+
+/// Time in ms the rendered game lags behind the newest snapshot by default
+pub const DEFAULT_RENDER_DELAY_MS: f64 = 100.0;
+
+/// Deviation in ms of the clock from the newest snapshot before it is reset
+const RESYNC_MS: f64 = 250.0;
+
+/// Time in ms the newest snapshot is extrapolated at most
+pub const MAX_EXTRAPOLATION_MS: f64 = 250.0;
+
+/// Buffer of timestamped snapshots.
+pub struct SnapshotBuffer {
+    /// Snapshots with their server time in ms, oldest first
+    snapshots: VecDeque<(f64, GameState)>,
+    /// Estimate of the current server time in ms
+    clock: f64,
+    /// Time in ms the rendered game lags behind the clock
+    delay: f64,
+}
+
+impl SnapshotBuffer {
+    pub fn new(delay: f64) -> SnapshotBuffer {
+        SnapshotBuffer {
+            snapshots: VecDeque::new(),
+            clock: 0.0,
+            delay: delay,
+        }
+    }
+
+    /// Add the snapshot of the given server tick.
+    pub fn push(&mut self, tick: u64, game: GameState) {
+        let time = tick as f64 * TICK_MS as f64;
+        if self.snapshots.back().map_or(false, |&(newest, _)| time <= newest) {
+            return;
+        }
+        if self.snapshots.is_empty() || (time - self.clock).abs() > RESYNC_MS {
+            self.clock = time;
+        }
+        self.snapshots.push_back((time, game));
+    }
+
+    /// Advance the clock by the elapsed time.
+    pub fn update(&mut self, dt_ms: f64) {
+        self.clock += dt_ms;
+    }
+
+    /// Return the newest snapshot together with its age in ms.
+    pub fn newest(&self) -> Option<(f64, &GameState)> {
+        self.snapshots.back().map(|&(time, ref game)| ((self.clock - time).max(0.0), game))
+    }
+
+    /// Return the game at the current render time.
+    ///
+    /// Units are interpolated between the surrounding snapshots. When the
+    /// buffer runs dry, the units of the newest snapshot keep moving for up to
+    /// `MAX_EXTRAPOLATION_MS`. Only the server decides about attacks.
+    pub fn state(&mut self) -> Option<GameState> {
+        let time = self.clock - self.delay;
+
+        // Snapshots before the one preceding the render time are not needed anymore
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= time {
+            self.snapshots.pop_front();
+        }
+
+        match (self.snapshots.get(0), self.snapshots.get(1)) {
+            (Some(&(t0, ref from)), Some(&(t1, ref to))) if time <= t1 => {
+                let alpha = ((time - t0) / (t1 - t0)).max(0.0);
+                Some(interpolate(from, to, alpha))
+            }
+            _ => {
+                self.snapshots.back().map(|&(t, ref newest)| {
+                    extrapolate(newest, (time - t).max(0.0).min(MAX_EXTRAPOLATION_MS))
+                })
+            }
+        }
+    }
+}
+
+/// Place the units of `to` between their positions in `from` and `to`.
+///
+/// Units that do not exist in `from` are taken as they are.
+pub fn interpolate(from: &GameState, to: &GameState, alpha: f64) -> GameState {
+    let mut game = to.clone();
+    for player in game.players.iter_mut() {
+        for unit in player.units.iter_mut() {
+            if let Some(old) = from.unit(unit.id) {
+                unit.position[0] = old.position[0] + (unit.position[0] - old.position[0]) * alpha;
+                unit.position[1] = old.position[1] + (unit.position[1] - old.position[1]) * alpha;
+                unit.angle = old.angle + angle_difference(old.angle, unit.angle) * alpha;
+            }
+        }
+    }
+    game
+}
+
+/// Move the units of the game along their speed vectors for `dt_ms`.
+pub fn extrapolate(game: &GameState, dt_ms: f64) -> GameState {
+    let mut game = game.clone();
+    for player in game.players.iter_mut() {
+        for unit in player.units.iter_mut() {
+            unit.position[0] += unit.speed_vector[0] * dt_ms;
+            unit.position[1] += unit.speed_vector[1] * dt_ms;
+        }
+    }
+    game
+}
+
+/// Return the shortest rotation from angle `a` to angle `b`.
+fn angle_difference(a: f64, b: f64) -> f64 {
+    let difference = (b - a) % (2.0 * PI);
+    if difference > PI {
+        difference - 2.0 * PI
+    } else if difference < -PI {
+        difference + 2.0 * PI
+    } else {
+        difference
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use super::{SnapshotBuffer, interpolate, angle_difference};
+    use settings::DEFAULT_UNIT_HEALTH;
+    use state::{GameState, Player, Unit, Faction};
+
+    fn game(position: [f64; 2], angle: f64) -> GameState {
+        let mut game = GameState::new();
+        let mut player = Player::new(0, Faction::Rock);
+        let mut unit = Unit::new(0, position, DEFAULT_UNIT_HEALTH);
+        unit.angle = angle;
+        player.units.push(unit);
+        game.players.push(player);
+        game
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let game = interpolate(&game([0.0, 0.0], 0.0), &game([10.0, 20.0], 1.0), 0.25);
+        let unit = &game.players[0].units[0];
+        assert_eq!(unit.position, [2.5, 5.0]);
+        assert_eq!(unit.angle, 0.25);
+    }
+
+    #[test]
+    fn test_angle_difference() {
+        assert!((angle_difference(0.1, 2.0 * PI - 0.1) + 0.2).abs() < 1e-9);
+        assert!((angle_difference(2.0 * PI - 0.1, 0.1) - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_render_delay() {
+        let mut buffer = SnapshotBuffer::new(20.0);
+        assert_eq!(buffer.state(), None);
+        buffer.push(0, game([0.0, 0.0], 0.0));
+        buffer.push(1, game([10.0, 0.0], 0.0));
+        buffer.push(2, game([20.0, 0.0], 0.0));
+        buffer.push(3, game([30.0, 0.0], 0.0));
+
+        // The clock was set by the first snapshot
+        buffer.update(25.0);
+        let game = buffer.state().unwrap();
+        assert_eq!(game.players[0].units[0].position, [5.0, 0.0]);
+
+        // Snapshots out of order are dropped
+        buffer.push(1, game.clone());
+        buffer.update(10.0);
+        assert_eq!(buffer.state().unwrap().players[0].units[0].position, [15.0, 0.0]);
+    }
+
+    #[test]
+    fn test_extrapolate_when_dry() {
+        let mut buffer = SnapshotBuffer::new(0.0);
+        let mut moving = game([0.0, 0.0], 0.0);
+        moving.players[0].units[0].speed_vector = [0.1, 0.0];
+        buffer.push(0, moving);
+        buffer.update(10.0);
+        assert_eq!(buffer.state().unwrap().players[0].units[0].position, [1.0, 0.0]);
+
+        // Units don't fly off while no snapshots arrive
+        buffer.update(10000.0);
+        assert_eq!(buffer.state().unwrap().players[0].units[0].position, [25.0, 0.0]);
+    }
+}
@@ -0,0 +1,281 @@
+//! The form to enter the address of the server to join.
+
+use std::net::SocketAddr;
+
+use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
+
+use piston::input::RenderArgs;
+
+use colors::{BLACK, YELLOW, ORANGE};
+use discovery::ServerInfo;
+use network::{Phase, ProtocolVersion};
+use transport::Transport;
+use super::recent::Address;
+
+/// Maximum length of a host name
+const MAX_HOST_LENGTH: usize = 253;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Field {
+    Host,
+    Port,
+}
+
+#[derive(Clone, Debug)]
+pub struct Join {
+    pub host: String,
+    pub port: String,
+    focus: Field,
+    /// Servers joined recently, the most recent first
+    recent: Vec<Address>,
+    /// Servers found in the local network
+    lan: Vec<(Address, ServerInfo)>,
+    /// Index of the recent or found server in the fields, if any
+    selected: Option<usize>,
+    /// Why the address in the fields was rejected, until it is edited
+    error: Option<String>,
+}
+
+impl Join {
+    pub fn new(address: &Address, recent: Vec<Address>) -> Join {
+        Join {
+            host: address.host.clone(),
+            port: address.port.to_string(),
+            focus: Field::Host,
+            selected: recent.iter().position(|other| other == address),
+            recent: recent,
+            lan: vec![],
+            error: None,
+        }
+    }
+
+    /// Update the servers found in the local network.
+    pub fn set_lan(&mut self, servers: &[(SocketAddr, ServerInfo)]) {
+        let lan: Vec<(Address, ServerInfo)> = servers.iter()
+            .map(|&(addr, ref info)| (Address { host: addr.ip().to_string(), port: addr.port() }, info.clone()))
+            .collect();
+        if lan != self.lan {
+            // The selection may refer to a server that is gone
+            if self.selected.map_or(false, |index| index >= self.recent.len()) {
+                self.selected = None;
+            }
+            self.lan = lan;
+        }
+    }
+
+    pub fn next_field(&mut self) {
+        self.focus = match self.focus {
+            Field::Host => Field::Port,
+            Field::Port => Field::Host,
+        };
+    }
+
+    /// Append typed text to the field with the focus.
+    ///
+    /// The port only takes digits.
+    pub fn insert(&mut self, text: &str) {
+        match self.focus {
+            Field::Host => {
+                for c in text.chars().filter(|c| !c.is_control() && !c.is_whitespace()) {
+                    if self.host.len() < MAX_HOST_LENGTH {
+                        self.host.push(c);
+                    }
+                }
+            }
+            Field::Port => {
+                for c in text.chars().filter(|c| c.is_digit(10)) {
+                    if self.port.len() < 5 {
+                        self.port.push(c);
+                    }
+                }
+            }
+        }
+        self.selected = None;
+        self.error = None;
+    }
+
+    pub fn backspace(&mut self) {
+        match self.focus {
+            Field::Host => self.host.pop(),
+            Field::Port => self.port.pop(),
+        };
+        self.selected = None;
+        self.error = None;
+    }
+
+    /// Fill in the next recent or found server.
+    pub fn next(&mut self) {
+        let index = self.selected.map_or(0, |index| index + 1);
+        self.select(index);
+    }
+
+    /// Fill in the previous recent or found server.
+    pub fn previous(&mut self) {
+        let index = match self.selected {
+            Some(0) | None => (self.recent.len() + self.lan.len()).saturating_sub(1),
+            Some(index) => index - 1,
+        };
+        self.select(index);
+    }
+
+    fn select(&mut self, index: usize) {
+        let address = match self.recent.get(index).or_else(|| {
+            self.lan.get(index - self.recent.len()).map(|&(ref address, _)| address)
+        }) {
+            Some(address) => address.clone(),
+            None => return,
+        };
+        self.host = address.host;
+        self.port = address.port.to_string();
+        self.selected = Some(index);
+        self.error = None;
+    }
+
+    /// Return the address in the fields.
+    pub fn address(&self) -> Result<Address, String> {
+        format!("{}:{}", self.host, self.port).parse()
+    }
+
+    /// Return the transport of the selected server, if it was found in the
+    /// local network.
+    pub fn transport(&self) -> Option<Transport> {
+        self.selected
+            .and_then(|index| index.checked_sub(self.recent.len()))
+            .and_then(|index| self.lan.get(index))
+            .map(|&(_, ref info)| info.transport)
+    }
+
+    /// Show why the address in the fields was rejected.
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_ref().map(|error| error.as_str())
+    }
+
+    pub fn render(&self, args: &RenderArgs, gl: &mut GlGraphics, cache: &mut GlyphCache) {
+        use graphics::{Text, clear, Transformed};
+        let text = Text::new_color(YELLOW, 64);
+        let field = Text::new_color(YELLOW, 32);
+        let field_focused = Text::new_color(ORANGE, 32);
+        let details = Text::new_color(ORANGE, 24);
+        let host = format!("Host: {}", self.host);
+        let port = format!("Port: {}", self.port);
+        gl.draw(args.viewport(), |c, gl| {
+            // Clear the screen.
+            clear(BLACK, gl);
+            let transform = c.transform.trans(0.0, 100.0);
+            text.draw("Join", cache, &c.draw_state, transform, gl);
+
+            let mut transform = transform.trans(0.0, 20.0);
+            for &(entry, label) in [(Field::Host, &host), (Field::Port, &port)].iter() {
+                transform = transform.trans(0.0, 50.0);
+                if entry == self.focus {
+                    field_focused.draw(label, cache, &c.draw_state, transform, gl);
+                } else {
+                    field.draw(label, cache, &c.draw_state, transform, gl);
+                }
+            }
+
+            if let Some(ref error) = self.error {
+                transform = transform.trans(0.0, 40.0);
+                field_focused.draw(error, cache, &c.draw_state, transform, gl);
+            }
+
+            transform = transform.trans(0.0, 50.0);
+            details.draw("Tab switches the field, Up and Down pick a server, F5 searches the LAN, \
+                          Escape goes back", cache, &c.draw_state, transform, gl);
+            let entries = self.recent.iter().map(|address| address.to_string())
+                .chain(self.lan.iter().map(|&(ref address, ref info)| describe(address, info)));
+            for (index, entry) in entries.enumerate() {
+                transform = transform.trans(0.0, 30.0);
+                let entry = if Some(index) == self.selected {
+                    format!("> {}", entry)
+                } else {
+                    format!("  {}", entry)
+                };
+                details.draw(&entry, cache, &c.draw_state, transform, gl);
+            }
+        });
+    }
+}
+
+/// Describe a server found in the local network.
+fn describe(address: &Address, info: &ServerInfo) -> String {
+    let phase = match info.phase {
+        Phase::Lobby | Phase::Countdown(_) => "lobby",
+        Phase::Running => "running",
+        Phase::Finished => "finished",
+    };
+    let transport = match info.transport {
+        Transport::Tcp => "TCP",
+        Transport::Udp => "UDP",
+    };
+    let compatible = if ProtocolVersion::current().is_compatible(&info.version) { "" } else { ", incompatible" };
+    format!("{} at {} over {}: {} of {} players, {}{}", info.name, address, transport, info.players,
+            info.max_players, phase, compatible)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Join, describe};
+    use client::recent::Address;
+    use discovery::ServerInfo;
+    use network::{Phase, ProtocolVersion};
+    use transport::Transport;
+
+    fn address(host: &str, port: u16) -> Address {
+        Address { host: host.into(), port: port }
+    }
+
+    #[test]
+    fn test_edit() {
+        let recent = vec![address("a", 1), address("b", 2)];
+        let mut join = Join::new(&address("127.0.0.1", 8080), recent);
+        join.backspace();
+        join.insert("2 3");
+        join.next_field();
+        join.backspace();
+        join.insert("1x");
+        assert_eq!(join.address(), Ok(address("127.0.0.23", 8081)));
+        join.insert("1");
+        let error = join.address().unwrap_err();
+        join.set_error(error.clone());
+        assert_eq!(join.error(), Some(error.as_str()));
+        join.backspace();
+        assert_eq!(join.error(), None);
+        join.insert("1");
+
+        join.next();
+        assert_eq!(join.address(), Ok(address("a", 1)));
+        join.next();
+        join.next();
+        assert_eq!(join.address(), Ok(address("b", 2)));
+        join.previous();
+        join.previous();
+        assert_eq!(join.address(), Ok(address("b", 2)));
+
+        let info = ServerInfo {
+            version: ProtocolVersion::current(),
+            name: "LAN".into(),
+            port: 8080,
+            transport: Transport::Udp,
+            players: 1,
+            max_players: 8,
+            phase: Phase::Lobby,
+        };
+        assert_eq!(describe(&address("192.168.1.2", 8080), &info),
+                   "LAN at 192.168.1.2:8080 over UDP: 1 of 8 players, lobby");
+        assert_eq!(join.transport(), None);
+        join.set_lan(&[("192.168.1.2:8080".parse().unwrap(), info)]);
+        join.next();
+        assert_eq!(join.address(), Ok(address("192.168.1.2", 8080)));
+        assert_eq!(join.transport(), Some(Transport::Udp));
+        join.set_lan(&[]);
+        join.next();
+        assert_eq!(join.address(), Ok(address("a", 1)));
+        assert_eq!(join.transport(), None);
+    }
+}
@@ -0,0 +1,66 @@
+//! The lobby shown until the match starts.
+
+use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
+
+use piston::input::RenderArgs;
+
+use colors;
+use colors::{BLACK, YELLOW, ORANGE};
+use network::{Phase, LobbyPlayer};
+use state::ClientId;
+
+/// Size of the square in the color of a player
+const MARKER_SIZE: f64 = 20.0;
+
+/// The phase and the players of the match as last announced by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lobby {
+    pub phase: Phase,
+    pub players: Vec<LobbyPlayer>,
+}
+
+impl Lobby {
+    pub fn new() -> Lobby {
+        Lobby {
+            phase: Phase::Lobby,
+            players: vec![],
+        }
+    }
+
+    pub fn is_ready(&self, id: ClientId) -> bool {
+        self.players.iter().any(|player| player.id == id && player.ready)
+    }
+
+    /// Draw the players in their colors, the own one is highlighted.
+    pub fn render(&self, own: Option<ClientId>, args: &RenderArgs, gl: &mut GlGraphics, cache: &mut GlyphCache) {
+        use graphics::{Text, clear, rectangle, Transformed};
+        let text = Text::new_color(YELLOW, 64);
+        let details = Text::new_color(ORANGE, 24);
+        let status = match self.phase {
+            Phase::Lobby => "Press Space when you are ready, Escape to leave".to_string(),
+            Phase::Countdown(seconds) => format!("The match starts in {} s", seconds),
+            Phase::Running => "The match starts".to_string(),
+            Phase::Finished => "The match is over".to_string(),
+        };
+        gl.draw(args.viewport(), |c, gl| {
+            // Clear the screen.
+            clear(BLACK, gl);
+            let transform = c.transform.trans(0.0, 100.0);
+            text.draw("Lobby", cache, &c.draw_state, transform, gl);
+            details.draw(&status, cache, &c.draw_state, transform.trans(0.0, 50.0), gl);
+
+            let mut transform = transform.trans(10.0, 80.0);
+            for player in self.players.iter() {
+                transform = transform.trans(0.0, 40.0);
+                let color = &colors::PLAYERS[player.faction.index() % colors::PLAYERS.len()];
+                rectangle(color.primary, [0.0, -MARKER_SIZE, MARKER_SIZE, MARKER_SIZE], transform, gl);
+                let entry = format!("Player {} ({:?}){}: {}", player.id, player.faction,
+                                    if Some(player.id) == own { ", you" } else { "" },
+                                    if player.ready { "ready" } else { "not ready" });
+                let text = Text::new_color(if player.ready { color.primary } else { color.secondary }, 24);
+                text.draw(&entry, cache, &c.draw_state, transform.trans(2.0 * MARKER_SIZE, 0.0), gl);
+            }
+        });
+    }
+}
@@ -8,16 +8,26 @@ use colors::{BLACK, YELLOW, ORANGE};
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Entries {
     Start,
+    Join,
     Exit,
 }
 
 impl Entries {
     pub fn next(&mut self) {
         *self = match *self {
-            Entries::Start => Entries::Exit,
+            Entries::Start => Entries::Join,
+            Entries::Join => Entries::Exit,
             Entries::Exit => Entries::Start,
         };
     }
+
+    pub fn previous(&mut self) {
+        *self = match *self {
+            Entries::Start => Entries::Exit,
+            Entries::Join => Entries::Start,
+            Entries::Exit => Entries::Join,
+        };
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -39,7 +49,7 @@ impl Menu {
             clear(BLACK, gl);
             let mut transform = c.transform;
 
-            for entry in &[Entries::Start, Entries::Exit] {
+            for entry in &[Entries::Start, Entries::Join, Entries::Exit] {
                 transform = transform.trans(0.0, 100.0);
                 if *entry == self.selected_entry {
                     text_selected.draw(&format!("{:?}", entry), cache, &c.draw_state, transform, gl);
@@ -55,7 +65,7 @@ impl Menu {
     }
 
     pub fn previous(&mut self) {
-        self.selected_entry.next();
+        self.selected_entry.previous();
     }
 
     pub fn next(&mut self) {
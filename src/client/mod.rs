@@ -1,4 +1,5 @@
 use std::sync::{Mutex, Arc};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::collections::VecDeque;
 use std::error::Error;
@@ -8,51 +9,142 @@ use opengl_graphics::glyph_cache::GlyphCache;
 use piston::input::{Button, Key, MouseButton, RenderArgs, UpdateArgs};
 
 use std::{thread, time};
-use std::net::TcpStream;
-use network::{Command, Message};
+use std::time::Instant;
+use network::{Command, Message, SessionToken, ProtocolVersion, Phase, FEATURE_DELTA, PING_INTERVAL_MS, IDLE_TIMEOUT_MS,
+              millis};
+use delta::SNAPSHOT_HISTORY;
+use transport::{self, Transport, Connection};
+use simulator::{self, Conditions};
+use discovery::{self, ServerInfo};
 
-use bincode::{serialize_into, deserialize_from, Infinite};
-
-use state::{UnitId, ClientId, WorldState, GameState};
+use settings::GameSettings;
+use state::{UnitId, BuildingId, ClientId, GameState};
 use shapes::Shape;
 use colors;
-use colors::{BLACK, ORANGE};
+use colors::{BLACK, YELLOW, ORANGE};
 
 pub mod menu;
 pub mod error;
+pub mod interpolation;
+pub mod prediction;
+pub mod reconnect;
+pub mod lobby;
+pub mod join;
+pub mod recent;
 
 use self::menu::Menu;
+use self::error::ServerError;
+use self::interpolation::{SnapshotBuffer, DEFAULT_RENDER_DELAY_MS, MAX_EXTRAPOLATION_MS};
+use self::prediction::Prediction;
+use self::reconnect::Reconnect;
+use self::lobby::Lobby;
+use self::join::Join;
+use self::recent::{Address, RecentServers};
+
+/// State of the connection to the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Open,
+    /// The connection broke or the server went silent
+    Lost,
+    /// The server closed the connection after the match
+    Finished,
+    /// The player left the server
+    Closed,
+}
+
+/// Time in ms to wait for servers in the local network to answer
+const DISCOVERY_TIMEOUT_MS: u64 = 1000;
 
 pub struct NetworkClient {
-    pub game_state: Arc<Mutex<Option<GameState>>>,
+    /// Received snapshots with their server tick
+    pub snapshots: Arc<Mutex<VecDeque<(u64, GameState)>>>,
     server_addr: SocketAddr,
-    stream: Option<TcpStream>,
-    commands: Arc<Mutex<VecDeque<Command>>>,
+    transport: Transport,
+    stream: Option<Box<Connection>>,
+    /// Simulated conditions of the network to the server
+    conditions: Conditions,
+    /// Commands with their sequence number, waiting to be sent
+    commands: Arc<Mutex<VecDeque<(u64, Command)>>>,
+    /// The protocol negotiated with the server
+    pub protocol: Option<ProtocolVersion>,
+    /// Last measured round trip time to the server in ms
+    pub rtt: Arc<Mutex<Option<u64>>>,
+    pub state: Arc<Mutex<ConnectionState>>,
+    /// The lobby as last announced by the server
+    pub lobby: Arc<Mutex<Lobby>>,
+    /// Whether the player is ready to start, waiting to be sent
+    pub ready: Arc<Mutex<Option<bool>>>,
 }
 
 impl NetworkClient {
     pub fn new<T: ToSocketAddrs>(server_addrs: T,
-                                 game_state: Arc<Mutex<Option<GameState>>>,
-                                 commands: Arc<Mutex<VecDeque<Command>>>) -> NetworkClient {
+                                 transport: Transport,
+                                 snapshots: Arc<Mutex<VecDeque<(u64, GameState)>>>,
+                                 commands: Arc<Mutex<VecDeque<(u64, Command)>>>) -> NetworkClient {
         let server_addr = server_addrs.to_socket_addrs().unwrap().next().unwrap();
         NetworkClient {
-            game_state: game_state,
+            snapshots: snapshots,
             server_addr: server_addr,
+            transport: transport,
             stream: None,
+            conditions: Conditions::default(),
             commands: commands,
+            protocol: None,
+            rtt: Arc::new(Mutex::new(None)),
+            state: Arc::new(Mutex::new(ConnectionState::Open)),
+            lobby: Arc::new(Mutex::new(Lobby::new())),
+            ready: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub fn connect(&mut self) -> Result<(ClientId, WorldState), Box<Error>>  {
-        let mut stream = TcpStream::connect(self.server_addr)?;
-        serialize_into(&mut stream, &Message::ClientHello, Infinite)?;
-        let server_hello = deserialize_from(&mut stream, Infinite);
+    /// Simulate the given network conditions on the connection to the server.
+    pub fn set_network_conditions(&mut self, conditions: Conditions) {
+        self.conditions = conditions;
+    }
+
+    pub fn connect(&mut self) -> Result<(ClientId, SessionToken, GameSettings), Box<Error>>  {
+        let stream = self.open()?;
+        self.handshake(stream, &Message::ClientHello(ProtocolVersion::current()))
+    }
+
+    /// Take over the player of a previous connection again.
+    pub fn reconnect(&mut self, id: ClientId, token: SessionToken)
+                     -> Result<(ClientId, SessionToken, GameSettings), Box<Error>> {
+        let stream = self.open()?;
+        self.handshake(stream, &Message::ClientReconnect(ProtocolVersion::current(), id, token))
+    }
+
+    fn open(&self) -> Result<Box<Connection>, Box<Error>> {
+        let stream = transport::connect(self.transport, self.server_addr)?;
+        Ok(simulator::simulate(stream, self.conditions)?)
+    }
+
+    /// Send the `ClientHello` or `ClientReconnect` message to the server at the
+    /// other end of the connection and wait for its answer.
+    pub fn handshake(&mut self, mut stream: Box<Connection>, hello: &Message)
+                     -> Result<(ClientId, SessionToken, GameSettings), Box<Error>> {
+        let client_version = ProtocolVersion::current();
+        stream.send(hello)?;
+        // Servers that accept connections but never answer are given up on
+        stream.set_read_timeout(Some(time::Duration::from_millis(IDLE_TIMEOUT_MS)))?;
+        let server_hello = stream.receive();
+        stream.set_read_timeout(None)?;
 
         self.stream = Some(stream);
-        if let Ok(Message::ServerHello(client_id, world_state)) = server_hello {
-            Ok((client_id, world_state))
-        } else {
-            Err("Could not connect to server".into())
+        match server_hello {
+            Ok(Message::ServerHello(protocol, client_id, token, settings)) => {
+                self.protocol = Some(protocol);
+                Ok((client_id, token, settings))
+            }
+            Ok(Message::VersionMismatch(server_version)) => {
+                Err(format!("Incompatible server: it speaks protocol version {}, we speak {}",
+                            server_version.version, client_version.version).into())
+            }
+            Ok(Message::Error(code, description)) => {
+                Err(Box::new(ServerError { code: code, description: description }))
+            }
+            _ => Err("Could not connect to server".into()),
         }
     }
 
@@ -60,42 +152,141 @@ impl NetworkClient {
         let stream = self.stream.as_ref().expect("Stream not here :(");
         let mut command_stream = stream.try_clone().unwrap();
         let commands = self.commands.clone();
+        // Tick of the last received snapshot, acknowledged by the sender loop
+        let received: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+        let received_clone = received.clone();
+        let deltas = self.protocol.map_or(false, |protocol| protocol.has_feature(FEATURE_DELTA));
+        // The timestamps of pings are relative to this
+        let epoch = Instant::now();
+        // Timestamps of the pings of the server, answered by the sender loop
+        let pings: Arc<Mutex<Vec<u64>>> = Arc::new(Mutex::new(vec![]));
+        let pings_clone = pings.clone();
+        // Time the last message of the server was received
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let last_seen_clone = last_seen.clone();
+        let state = self.state.clone();
+        let state_clone = self.state.clone();
+        let ready = self.ready.clone();
 
         // Command sender loop
         thread::spawn(move || {
+            let mut acked = None;
+            let mut last_ping: Option<Instant> = None;
             loop {
+                if *state_clone.lock().unwrap() != ConnectionState::Open {
+                    command_stream.shutdown();
+                    return;
+                }
+                // A half-open connection does not fail, but the server stops pinging
+                if millis(last_seen_clone.lock().unwrap().elapsed()) > IDLE_TIMEOUT_MS {
+                    println!("The server went silent");
+                    *state_clone.lock().unwrap() = ConnectionState::Lost;
+                    command_stream.shutdown();
+                    return;
+                }
                 let command = {
                     let mut commands = commands.lock().unwrap();
                     commands.pop_front()
                 };
-                command.map(|cmd| {
-                    println!("Got command: {:?}", cmd);
-                    //let cmd = Message::Command(Command::Move(0.into(), [100f64, 100f64]));
-                    serialize_into(&mut command_stream, &Message::Command(cmd), Infinite)
+                command.map(|(sequence, cmd)| {
+                    println!("Got command {}: {:?}", sequence, cmd);
+                    command_stream.send(&Message::Command(sequence, cmd))
                         .unwrap_or_else(|e|println!("Sending command failed: {}", e));
                 });
+                let tick = *received_clone.lock().unwrap();
+                if deltas && tick != acked {
+                    if let Some(tick) = tick {
+                        command_stream.send(&Message::Ack(tick))
+                            .unwrap_or_else(|e|println!("Sending ack failed: {}", e));
+                    }
+                    acked = tick;
+                }
+                if let Some(ready) = ready.lock().unwrap().take() {
+                    command_stream.send(&Message::Ready(ready))
+                        .unwrap_or_else(|e|println!("Sending ready failed: {}", e));
+                }
+                for timestamp in pings_clone.lock().unwrap().drain(..) {
+                    command_stream.send(&Message::Pong(timestamp))
+                        .unwrap_or_else(|e|println!("Sending pong failed: {}", e));
+                }
+                if last_ping.map_or(true, |ping| millis(ping.elapsed()) >= PING_INTERVAL_MS) {
+                    command_stream.send(&Message::Ping(millis(epoch.elapsed())))
+                        .unwrap_or_else(|e|println!("Sending ping failed: {}", e));
+                    last_ping = Some(Instant::now());
+                }
                 thread::sleep(time::Duration::from_millis(10));
             }
         });
 
         let mut game_state_stream = stream.try_clone().unwrap();
-        let game_state = self.game_state.clone();
+        let snapshots = self.snapshots.clone();
+        let rtt = self.rtt.clone();
+        let lobby = self.lobby.clone();
         thread::spawn(move || {
+            // Recent snapshots that the server may use as baseline
+            let mut history: VecDeque<(u64, GameState)> = VecDeque::with_capacity(SNAPSHOT_HISTORY);
             loop {
-                let game_state_server: Result<GameState,_> = deserialize_from(
-                    &mut game_state_stream,
-                    Infinite);
-                match game_state_server {
-                    Ok(game) => {
-                        //println!("{:?}", game);
-                        let mut game_state_lock = game_state.lock().unwrap();
-                        *game_state_lock = Some(game);
-
+                let message = game_state_stream.receive();
+                if message.is_ok() {
+                    *last_seen.lock().unwrap() = Instant::now();
+                }
+                let snapshot = match message {
+                    Ok(Message::UpdateGamestate(tick, game)) => Some((tick, game)),
+                    Ok(Message::DeltaGamestate(tick, delta)) => {
+                        match history.iter().find(|&&(t, _)| t == delta.baseline) {
+                            Some(&(_, ref baseline)) => Some((tick, delta.apply(baseline))),
+                            None => {
+                                println!("Baseline {} of snapshot {} is missing", delta.baseline, tick);
+                                None
+                            }
+                        }
+                    }
+                    Ok(Message::GameOver(results)) => {
+                        println!("Game over: {:?}", results);
+                        *state.lock().unwrap() = ConnectionState::Finished;
+                        return;
+                    }
+                    Ok(Message::PlayerLeft(id)) => {
+                        println!("Player {} left", id);
+                        None
+                    }
+                    Ok(Message::Lobby(phase, players)) => {
+                        *lobby.lock().unwrap() = Lobby { phase: phase, players: players };
+                        None
+                    }
+                    Ok(Message::Ping(timestamp)) => {
+                        pings.lock().unwrap().push(timestamp);
+                        None
+                    }
+                    Ok(Message::Pong(timestamp)) => {
+                        *rtt.lock().unwrap() = Some(millis(epoch.elapsed()).saturating_sub(timestamp));
+                        None
+                    }
+                    Ok(Message::Error(code, description)) => {
+                        println!("{}: {}", code, description);
+                        None
+                    }
+                    Ok(message) => {
+                        println!("Did receive unexpected message: {:?}", message);
+                        None
                     }
                     Err(e) => {
                         println!("{:?}", e);
-                        thread::sleep(time::Duration::from_millis(200));
+                        let mut state = state.lock().unwrap();
+                        if *state == ConnectionState::Open {
+                            *state = ConnectionState::Lost;
+                        }
+                        game_state_stream.shutdown();
+                        return;
                     }
+                };
+                if let Some((tick, game)) = snapshot {
+                    snapshots.lock().unwrap().push_back((tick, game.clone()));
+                    *received.lock().unwrap() = Some(tick);
+                    if history.len() == SNAPSHOT_HISTORY {
+                        history.pop_front();
+                    }
+                    history.push_back((tick, game));
                 }
             }
         });
@@ -105,85 +296,357 @@ impl NetworkClient {
 #[derive(Clone, Debug)]
 pub enum State {
     Menu,
+    /// Entering the address of the server
+    Join(Join),
     Error(error::Message),
+    /// Waiting for the match to start
+    Lobby,
     Running,
+    /// The connection was lost, the game is shown until it is back
+    Reconnecting(Reconnect),
+}
+
+/// Resolve the address of the server and prepare a client for it.
+fn network_client(address: &Address,
+                  transport: Transport,
+                  conditions: Conditions,
+                  snapshots: Arc<Mutex<VecDeque<(u64, GameState)>>>,
+                  commands: Arc<Mutex<VecDeque<(u64, Command)>>>) -> Result<NetworkClient, Box<Error>> {
+    let server_addr = (address.host.as_str(), address.port).to_socket_addrs()?.next()
+        .ok_or_else(|| format!("Could not resolve {}", address))?;
+    let mut network_client = NetworkClient::new(server_addr, transport, snapshots, commands);
+    network_client.set_network_conditions(conditions);
+    Ok(network_client)
 }
 
+/// A reconnect attempt that did not succeed.
+struct Failure {
+    message: error::Message,
+    /// Whether the server will not accept the player anymore
+    refused: bool,
+}
+
+/// Outcome of a reconnect attempt: the connected client, its new session
+/// token and the settings of the match.
+type Attempt = Result<(NetworkClient, SessionToken, GameSettings), Failure>;
+
 pub struct App {
     pub gl: GlGraphics, // OpenGL drawing backend.
-    pub world_state: Option<WorldState>,
-    pub game_state_server: Arc<Mutex<Option<GameState>>>,
+    /// Settings of the match as sent by the server
+    pub settings: GameSettings,
+    /// Snapshots received by the network client
+    pub game_state_server: Arc<Mutex<VecDeque<(u64, GameState)>>>,
+    /// Snapshots waiting to be rendered
+    pub snapshots: SnapshotBuffer,
     pub game_state: GameState,
     pub selected_units: Vec<UnitId>,
-    pub commands: Arc<Mutex<VecDeque<Command>>>,
+    pub selected_buildings: Vec<BuildingId>,
+    pub commands: Arc<Mutex<VecDeque<(u64, Command)>>>,
+    /// Own commands that have not been processed by the server yet
+    pub prediction: Prediction,
     pub cursor: [f64; 2],
     pub state: State,
     zoom: f64,
     scroll: [f64; 2],
     menu: Menu,
     client_id: Option<ClientId>,
+    session_token: Option<SessionToken>,
+    /// The server to connect to
+    address: Address,
+    recent: RecentServers,
+    /// Servers found in the local network
+    lan: Arc<Mutex<Vec<(SocketAddr, ServerInfo)>>>,
+    /// Transport chosen by the player, servers in the local network announce their own
+    transport: Transport,
+    /// Transport of the connection to the server, also used to reconnect
+    session_transport: Transport,
+    conditions: Conditions,
+    /// Last measured round trip time to the server in ms
+    rtt: Arc<Mutex<Option<u64>>>,
+    connection: Arc<Mutex<ConnectionState>>,
+    lobby: Arc<Mutex<Lobby>>,
+    ready: Arc<Mutex<Option<bool>>>,
+    /// Whether the player asked to be ready to start
+    is_ready: bool,
+    /// Reconnect attempt running in the background
+    attempt: Option<Receiver<Attempt>>,
 }
 
 impl App {
     pub fn new(gl: GlGraphics) -> App {
         App {
             gl: gl,
-            world_state: None,
-            game_state_server: Arc::new(Mutex::new(None)),
+            settings: GameSettings::default(),
+            game_state_server: Arc::new(Mutex::new(VecDeque::new())),
+            snapshots: SnapshotBuffer::new(DEFAULT_RENDER_DELAY_MS),
             game_state: GameState::new(),
             selected_units: vec![],
+            selected_buildings: vec![],
             commands: Arc::new(Mutex::new(VecDeque::new())),
+            prediction: Prediction::new(),
             cursor: [0.0, 0.0],
             state: State::Menu,
             zoom: 1.0,
             scroll: [0.0, 0.0],
             menu: Menu::new(),
             client_id: None,
+            session_token: None,
+            address: Address { host: "127.0.0.1".into(), port: 8080 },
+            recent: RecentServers::new(),
+            lan: Arc::new(Mutex::new(vec![])),
+            transport: Transport::Tcp,
+            session_transport: Transport::Tcp,
+            conditions: Conditions::default(),
+            rtt: Arc::new(Mutex::new(None)),
+            connection: Arc::new(Mutex::new(ConnectionState::Open)),
+            lobby: Arc::new(Mutex::new(Lobby::new())),
+            ready: Arc::new(Mutex::new(None)),
+            is_ready: false,
+            attempt: None,
         }
     }
 
-    pub fn start(&mut self) -> Result<(), Box<Error>> {
-        let mut network_client = NetworkClient::new(
-            ("127.0.0.1", 8080),
-            self.game_state_server.clone(),
-            self.commands.clone());
-        let (client_id, world_state) = network_client.connect()?;
+    /// Set the time in ms the rendered game lags behind the server.
+    ///
+    /// A longer delay hides more network jitter, at the cost of latency.
+    pub fn set_render_delay(&mut self, delay_ms: f64) {
+        self.snapshots = SnapshotBuffer::new(delay_ms);
+    }
+
+    /// Set the server that is joined with the Start entry of the menu.
+    pub fn set_server(&mut self, address: Address) {
+        self.address = address;
+    }
+
+    /// Set the servers the player joined recently, they are offered when joining.
+    pub fn set_recent_servers(&mut self, recent: RecentServers) {
+        self.recent = recent;
+    }
+
+    /// Set the transport used to connect to the server.
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Simulate the given network conditions on the connection to the server.
+    pub fn set_network_conditions(&mut self, conditions: Conditions) {
+        self.conditions = conditions;
+    }
+
+    /// Connect to the server over the given transport.
+    pub fn start(&mut self, transport: Transport) -> Result<(), Box<Error>> {
+        let mut network_client = network_client(&self.address, transport, self.conditions,
+                                                self.game_state_server.clone(), self.commands.clone())?;
+        let (client_id, token, settings) = network_client.connect()?;
         self.client_id = Some(client_id);
-        self.world_state = Some(world_state);
-        network_client.update();
+        self.session_transport = transport;
+        self.run(network_client, token, settings);
+
+        self.recent.add(self.address.clone());
+        if let Err(e) = self.recent.save() {
+            println!("Could not save the recent servers: {}", e);
+        }
         Ok(())
     }
 
+    /// Connect to the server and wait in the lobby for the match to start.
+    fn join(&mut self, transport: Transport) {
+        self.state = match self.start(transport) {
+            Ok(()) => State::Lobby,
+            Err(err) => State::Error(error::Message::from_error(&*err)),
+        };
+    }
+
+    /// Search for servers in the local network in the background.
+    fn discover(&self) {
+        let lan = self.lan.clone();
+        thread::spawn(move || {
+            match discovery::discover(time::Duration::from_millis(DISCOVERY_TIMEOUT_MS)) {
+                Ok(servers) => *lan.lock().unwrap() = servers,
+                Err(e) => println!("Could not search the local network: {}", e),
+            }
+        });
+    }
+
+    /// Join the server entered in the form.
+    fn join_entered(&mut self) {
+        let (address, transport) = match self.state {
+            State::Join(ref join) => (join.address(), join.transport()),
+            _ => return,
+        };
+        match address {
+            Ok(address) => {
+                self.address = address;
+                // Servers in the local network announce their transport
+                let transport = transport.unwrap_or(self.transport);
+                self.join(transport);
+            }
+            // Keep the form, so that the address can be corrected
+            Err(e) => {
+                if let State::Join(ref mut join) = self.state {
+                    join.set_error(e);
+                }
+            }
+        }
+    }
+
+    /// Start taking over the own player again after the connection was lost.
+    ///
+    /// The attempt runs on another thread, so that the game keeps rendering
+    /// while the server is slow to answer. Its outcome arrives on the returned
+    /// channel.
+    fn reconnect(&self) -> Receiver<Attempt> {
+        let session = match (self.client_id, self.session_token) {
+            (Some(id), Some(token)) => Some((id, token)),
+            _ => None,
+        };
+        let address = self.address.clone();
+        let transport = self.session_transport;
+        let conditions = self.conditions;
+        let snapshots = self.game_state_server.clone();
+        let commands = self.commands.clone();
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let attempt = || -> Result<(NetworkClient, SessionToken, GameSettings), Box<Error>> {
+                let (id, token) = session.ok_or("Not connected before")?;
+                let mut network_client = network_client(&address, transport, conditions, snapshots, commands)?;
+                let (_, token, settings) = network_client.reconnect(id, token)?;
+                Ok((network_client, token, settings))
+            };
+            let attempt = attempt().map_err(|err| {
+                println!("Reconnecting failed: {}", err);
+                Failure {
+                    message: error::Message::from_error(&*err),
+                    refused: err.downcast_ref::<ServerError>().is_some(),
+                }
+            });
+            // The player may have given up in the meantime
+            let _ = sender.send(attempt);
+        });
+        receiver
+    }
+
+    fn run(&mut self, network_client: NetworkClient, token: SessionToken, settings: GameSettings) {
+        self.session_token = Some(token);
+        self.settings = settings;
+        self.rtt = network_client.rtt.clone();
+        self.connection = network_client.state.clone();
+        self.lobby = network_client.lobby.clone();
+        self.ready = network_client.ready.clone();
+        self.is_ready = false;
+        network_client.update();
+    }
+
+    /// Tell the server that the player is ready to start, or not anymore.
+    ///
+    /// The lobby announced by the server lags behind, so repeated toggles
+    /// go by what the player asked for last.
+    pub fn toggle_ready(&mut self) {
+        self.is_ready = !self.is_ready;
+        *self.ready.lock().unwrap() = Some(self.is_ready);
+    }
+
+    /// Leave the server while waiting in the lobby.
+    fn leave(&mut self) {
+        *self.connection.lock().unwrap() = ConnectionState::Closed;
+        self.state = State::Menu;
+    }
+
+    /// Start the next attempt to reconnect when it is due, and pick up the
+    /// outcome of the running one.
+    fn update_reconnect(&mut self, dt_ms: f64) {
+        let attempt = match self.attempt {
+            Some(ref attempt) => match attempt.try_recv() {
+                Ok(attempt) => attempt,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Err(Failure {
+                    message: error::Message::new("Reconnecting failed".into()),
+                    refused: false,
+                }),
+            },
+            None => {
+                let due = match self.state {
+                    State::Reconnecting(ref mut reconnect) => reconnect.update(dt_ms),
+                    _ => false,
+                };
+                if due {
+                    self.attempt = Some(self.reconnect());
+                }
+                return;
+            }
+        };
+        self.attempt = None;
+        self.state = match (attempt, self.state.clone()) {
+            (Ok((network_client, token, settings)), _) => {
+                self.run(network_client, token, settings);
+                State::Running
+            }
+            (Err(failure), State::Reconnecting(reconnect)) => {
+                match reconnect.retry() {
+                    Some(next) if !failure.refused => State::Reconnecting(next),
+                    _ => State::Error(failure.message),
+                }
+            }
+            (Err(failure), _) => State::Error(failure.message),
+        };
+    }
+
     pub fn select(&mut self, position: [f64;2]) {
 
         let player = {
-            let index = self.client_id.unwrap_or(ClientId(0)).0 as usize;
-            self.game_state.players.get(index).map(|v| v.clone())
+            let id = self.client_id.unwrap_or(ClientId(0));
+            self.game_state.player(id).map(|v| v.clone())
         };
 
         self.selected_units.truncate(0);
+        self.selected_buildings.truncate(0);
+        let size = self.settings.unit_size;
         if let Some(player) = player {
             for unit in player.units.iter() {
-                if unit.is_hit(50.0, position) {
+                if unit.is_hit(size, position) {
+                    self.selected_units.push(unit.id);
+                }
+            }
+            for building in player.buildings.iter() {
+                if building.is_hit(size, position) {
+                    self.selected_buildings.push(building.id);
+                }
+            }
+        }
+    }
+
+    /// Add the own unit at the specified position to the selection.
+    pub fn select_more(&mut self, position: [f64;2]) {
+        let id = self.client_id.unwrap_or(ClientId(0));
+        let size = self.settings.unit_size;
+        if let Some(player) = self.game_state.player(id) {
+            for unit in player.units.iter() {
+                if unit.is_hit(size, position) && !self.selected_units.contains(&unit.id) {
                     self.selected_units.push(unit.id);
                 }
             }
         }
     }
 
-    fn render_game(&mut self, args: &RenderArgs, _: &mut GlyphCache) {
-        use graphics::{polygon, line, clear};
+    fn render_game(&mut self, args: &RenderArgs, cache: &mut GlyphCache) {
+        use graphics::{polygon, line, ellipse, clear, Text};
         use graphics::Transformed;
         use graphics::types::{Polygon, Line};
 
         const FRONT_THICKNESS: f64 = 5.0;
+        const PROJECTILE_RADIUS: f64 = 2.0;
 
         let game_state = &self.game_state;
-        let world = self.world_state.as_ref().unwrap();
-        let (wx, wy) = (world.x, world.y);
+        let (wx, wy) = (self.settings.world.x, self.settings.world.y);
+        let size = self.settings.unit_size;
         let zoom = self.zoom;
         let scroll = self.scroll;
         let selected_units = self.selected_units.clone();
+        let selected_buildings = self.selected_buildings.clone();
+        let hud = match *self.rtt.lock().unwrap() {
+            Some(rtt) => format!("RTT: {} ms", rtt),
+            None => "RTT: -".to_string(),
+        };
 
         self.gl.draw(args.viewport(), |c, gl| {
 
@@ -205,11 +668,8 @@ impl App {
                 line(ORANGE, 1.0, *l, transform, gl);
             }
 
-            let size = 50.0;
-        
-            for i in 0..game_state.players.len() {
-                let ref player = game_state.players[i];
-                let color = &colors::PLAYERS[i % colors::PLAYERS.len()];
+            for player in game_state.players.iter() {
+                let color = &colors::PLAYERS[player.faction.index() % colors::PLAYERS.len()];
                 for s in player.units.iter() {
                     // Create a triangle polygon. The initial orientation is facing east.
                     let triangle: Polygon = &s.get_shape(size);
@@ -226,7 +686,7 @@ impl App {
                     // Rotate the front to match the unit
                     let transform_front = transform.trans(s.position[0], s.position[1])
                         .rot_rad(s.angle)
-                        .trans(-25.0, -25.0);
+                        .trans(-size / 2.0, -size / 2.0);
 
                     // We don't need to apply any transformation to the units
                     let transform_triangle = transform;
@@ -242,7 +702,41 @@ impl App {
                     }
 
                 }
+
+                for b in player.buildings.iter() {
+                    // Draw a square with a roof that is smaller by the front thickness on each side
+                    let square: Polygon = &b.get_shape(size);
+                    let roof: Polygon = &[
+                        [FRONT_THICKNESS, FRONT_THICKNESS],
+                        [size - FRONT_THICKNESS, FRONT_THICKNESS],
+                        [size - FRONT_THICKNESS, size - FRONT_THICKNESS],
+                        [FRONT_THICKNESS, size - FRONT_THICKNESS],
+                    ];
+                    let transform_roof = transform.trans(b.position[0], b.position[1])
+                        .rot_rad(b.angle)
+                        .trans(-size / 2.0, -size / 2.0);
+
+                    let selected = selected_buildings.iter().any(|id| id == &b.id);
+                    if selected {
+                        polygon(color.secondary, square, transform, gl);
+                        polygon(color.primary, roof, transform_roof, gl);
+                    } else {
+                        polygon(color.primary, square, transform, gl);
+                        polygon(color.secondary, roof, transform_roof, gl);
+                    }
+                }
             }
+
+            // Draw projectiles as dots in the color of the player that fired them
+            for projectile in game_state.projectiles.iter() {
+                let color = game_state.player(projectile.owner)
+                    .map_or(ORANGE, |player| colors::PLAYERS[player.faction.index() % colors::PLAYERS.len()].primary);
+                let dot = ellipse::circle(projectile.position[0], projectile.position[1], PROJECTILE_RADIUS);
+                ellipse(color, dot, transform, gl);
+            }
+
+            // The HUD is not zoomed or scrolled
+            Text::new_color(YELLOW, 16).draw(&hud, cache, &c.draw_state, c.transform.trans(10.0, 20.0), gl);
         });
     }
 
@@ -250,20 +744,56 @@ impl App {
         match self.state {
             State::Menu => self.menu.render(args, &mut self.gl, cache),
             State::Running => self.render_game(args, cache),
+            State::Join(ref join) => join.render(args, &mut self.gl, cache),
             State::Error(ref msg) => msg.render(args, &mut self.gl, cache),
+            State::Lobby => self.lobby.lock().unwrap().render(self.client_id, args, &mut self.gl, cache),
+            State::Reconnecting(_) => {
+                self.render_game(args, cache);
+                if let State::Reconnecting(ref reconnect) = self.state {
+                    reconnect.render(args, &mut self.gl, cache);
+                }
+            }
         }
     }
 
     pub fn update(&mut self, args: &UpdateArgs) {
-        // grab updated server state if it is available
-        let game_state_option = {
-            let mut game_state_lock = self.game_state_server.lock().unwrap();
-            mem::replace(&mut *game_state_lock, None)
-        };
-        if let Some(game_state) = game_state_option {
+        match self.state {
+            State::Running if *self.connection.lock().unwrap() == ConnectionState::Lost => {
+                self.attempt = None;
+                self.state = State::Reconnecting(Reconnect::new());
+            }
+            State::Reconnecting(_) => self.update_reconnect(args.dt * 1000.0),
+            State::Join(ref mut join) => join.set_lan(&self.lan.lock().unwrap()),
+            State::Lobby if self.lobby.lock().unwrap().phase == Phase::Running => {
+                self.state = State::Running;
+            }
+            // The server forgets players that leave the lobby
+            State::Lobby if *self.connection.lock().unwrap() != ConnectionState::Open => {
+                self.state = State::Error(error::Message::new("Lost the connection to the server".into()));
+            }
+            _ => {}
+        }
+
+        // grab updated server states if they are available
+        let snapshots = mem::replace(&mut *self.game_state_server.lock().unwrap(), VecDeque::new());
+        for (tick, game_state) in snapshots {
+            self.snapshots.push(tick, game_state);
+        }
+        self.snapshots.update(args.dt*1000.0);
+        if let Some(mut game_state) = self.snapshots.state() {
+            // Show the own units at the current time instead of the render delay
+            // in the past, including the effects of commands still on the way
+            if let (Some(id), Some((age, newest))) = (self.client_id, self.snapshots.newest()) {
+                if let Some(player) = newest.player(id) {
+                    self.prediction.reconcile(player.last_command);
+                    let age = age.min(MAX_EXTRAPOLATION_MS);
+                    let predicted = self.prediction.predict(player, age, self.settings.unit_speed);
+                    if let Some(own) = game_state.player_mut(id) {
+                        *own = predicted;
+                    }
+                }
+            }
             self.game_state = game_state;
-        } else {
-            self.game_state.update(args.dt*1000.0);
         }
     }
 
@@ -280,14 +810,12 @@ impl App {
                     &Button::Keyboard(Key::Return) => {
                         match self.menu.get_selected_entry() {
                             menu::Entries::Start => {
-                                match self.start() {
-                                    Ok(_) => {
-                                        self.state = State::Running;
-                                    }
-                                    Err(err) => {
-                                        self.state = State::Error(error::Message::new(err.description().into()));
-                                    }
-                                }
+                                let transport = self.transport;
+                                self.join(transport);
+                            }
+                            menu::Entries::Join => {
+                                self.state = State::Join(Join::new(&self.address, self.recent.addresses.clone()));
+                                self.discover();
                             }
                             menu::Entries::Exit => {
                                 return true;
@@ -311,6 +839,12 @@ impl App {
                     &Button::Keyboard(Key::Right) => {
                         self.scroll[0] -= 10.0;
                     }
+                    &Button::Keyboard(Key::M) => {
+                        self.merge_selected();
+                    }
+                    &Button::Keyboard(Key::S) => {
+                        self.split_selected();
+                    }
                     &Button::Keyboard(_) => { }
                     &Button::Mouse(button) => {
                         self.on_mouse_click(&button);
@@ -318,12 +852,47 @@ impl App {
                     &Button::Controller(_) => { }
                 }
             }
+            State::Join(_) => {
+                let submit = match self.state {
+                    State::Join(ref mut join) => {
+                        match button {
+                            &Button::Keyboard(Key::Tab) => join.next_field(),
+                            &Button::Keyboard(Key::Backspace) => join.backspace(),
+                            &Button::Keyboard(Key::Up) => join.previous(),
+                            &Button::Keyboard(Key::Down) => join.next(),
+                            _ => { }
+                        }
+                        button == &Button::Keyboard(Key::Return)
+                    }
+                    _ => false,
+                };
+                if submit {
+                    self.join_entered();
+                } else if button == &Button::Keyboard(Key::F5) {
+                    self.discover();
+                } else if button == &Button::Keyboard(Key::Escape) {
+                    self.state = State::Menu;
+                }
+            }
+            State::Lobby => {
+                match button {
+                    &Button::Keyboard(Key::Space) | &Button::Keyboard(Key::Return) => {
+                        self.toggle_ready();
+                    }
+                    &Button::Keyboard(Key::Escape) => {
+                        self.leave();
+                    }
+                    _ => { }
+                }
+            }
             State::Error(_) => {
                 match button {
                     &Button::Keyboard(_) => { self.state = State::Menu; }
                     _ => { }
                 }
             }
+            // Commands would be lost
+            State::Reconnecting(_) => { }
         };
         false
     }
@@ -335,11 +904,19 @@ impl App {
         ];
         match *button {
             MouseButton::Left  => self.select(cursor),
+            MouseButton::Middle => self.select_more(cursor),
             MouseButton::Right => self.move_selected(cursor),
             _ => println!("Pressed mouse button '{:?}'", button),
         }
     }
 
+    /// Enter text into the form with the focus.
+    pub fn on_text(&mut self, text: &str) {
+        if let State::Join(ref mut join) = self.state {
+            join.insert(text);
+        }
+    }
+
     pub fn on_mouse_move(&mut self, cursor: [f64; 2]) {
         self.cursor = cursor;
     }
@@ -354,9 +931,155 @@ impl App {
     }
 
     pub fn move_selected(&mut self, position: [f64;2]) {
-        for u in self.selected_units.iter() {
-            let mut commands = self.commands.lock().unwrap();
-            commands.push_back(Command::Move(*u, position));
+        for u in self.selected_units.clone() {
+            self.send(Command::Move(u, position));
+        }
+    }
+
+    /// Merge the two selected units into a building.
+    pub fn merge_selected(&mut self) {
+        if self.selected_units.len() != 2 {
+            println!("Select exactly two units to merge them");
+            return;
+        }
+        let command = Command::Merge(self.selected_units[0], self.selected_units[1]);
+        self.send(command);
+        self.selected_units.truncate(0);
+    }
+
+    /// Split the selected buildings back into units.
+    pub fn split_selected(&mut self) {
+        for b in mem::replace(&mut self.selected_buildings, vec![]) {
+            self.send(Command::Split(b));
+        }
+    }
+
+    /// Queue the command for the server and predict its effect.
+    fn send(&mut self, command: Command) {
+        let sequence = self.prediction.issue(command.clone());
+        self.commands.lock().unwrap().push_back((sequence, command));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{NetworkClient, ConnectionState};
+    use super::error::ServerError;
+    use super::lobby::Lobby;
+    use network::{Message, ErrorCode, ProtocolVersion, SessionToken, Phase, LobbyPlayer};
+    use settings::GameSettings;
+    use state::Faction;
+    use transport::{Connection, MemoryConnection, Transport, pair};
+
+    fn client() -> NetworkClient {
+        NetworkClient::new(("127.0.0.1", 8080), Transport::Tcp,
+                           Arc::new(Mutex::new(VecDeque::new())), Arc::new(Mutex::new(VecDeque::new())))
+    }
+
+    /// Create a client that completed the handshake and runs its network
+    /// loops, together with the server end of its connection.
+    fn connected() -> (NetworkClient, MemoryConnection) {
+        let (local, mut remote) = pair();
+        let settings = GameSettings::default();
+        let hello = Message::ServerHello(ProtocolVersion::current(), 3.into(), SessionToken::generate(), settings);
+        remote.send(&hello).unwrap();
+        let mut client = client();
+        client.handshake(Box::new(local), &Message::ClientHello(ProtocolVersion::current())).unwrap();
+        client.update();
+        // The ClientHello
+        remote.receive().unwrap();
+        (client, remote)
+    }
+
+    /// Wait up to a second for the condition to become true.
+    fn wait_until<F: Fn() -> bool>(what: &str, condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
         }
+        panic!("Timed out waiting until {}", what);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (local, mut remote) = pair();
+        let token = SessionToken::generate();
+        let server = thread::spawn(move || {
+            match remote.receive().unwrap() {
+                Message::ClientHello(version) => {
+                    let protocol = ProtocolVersion::current().negotiate(&version);
+                    let settings = GameSettings::default();
+                    remote.send(&Message::ServerHello(protocol, 3.into(), token, settings)).unwrap();
+                }
+                other => panic!("Expected ClientHello, got {:?}", other),
+            }
+        });
+
+        let mut client = client();
+        let hello = Message::ClientHello(ProtocolVersion::current());
+        let (id, received_token, settings) = client.handshake(Box::new(local), &hello).unwrap();
+        server.join().unwrap();
+        assert_eq!(id, 3.into());
+        assert_eq!(received_token, token);
+        assert_eq!(settings, GameSettings::default());
+        assert_eq!(client.protocol, Some(ProtocolVersion::current()));
+    }
+
+    #[test]
+    fn test_connection_lost() {
+        let (client, remote) = connected();
+        assert_eq!(*client.state.lock().unwrap(), ConnectionState::Open);
+
+        remote.shutdown();
+        wait_until("the lost connection is noticed", || *client.state.lock().unwrap() == ConnectionState::Lost);
+    }
+
+    #[test]
+    fn test_leave() {
+        let (client, mut remote) = connected();
+        *client.state.lock().unwrap() = ConnectionState::Closed;
+        // The connection is closed once the pending messages have been received
+        while remote.receive().is_ok() {}
+        assert_eq!(*client.state.lock().unwrap(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_lobby() {
+        let (client, mut remote) = connected();
+
+        *client.ready.lock().unwrap() = Some(true);
+        loop {
+            match remote.receive().unwrap() {
+                Message::Ready(ready) => {
+                    assert!(ready);
+                    break;
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+                other => panic!("Expected Ready, got {:?}", other),
+            }
+        }
+
+        let players = vec![LobbyPlayer { id: 3.into(), faction: Faction::for_player(0), ready: true }];
+        remote.send(&Message::Lobby(Phase::Countdown(3), players.clone())).unwrap();
+        let expected = Lobby { phase: Phase::Countdown(3), players: players };
+        wait_until("the lobby is updated", || *client.lobby.lock().unwrap() == expected);
+        assert!(expected.is_ready(3.into()));
+    }
+
+    #[test]
+    fn test_handshake_error() {
+        let (local, mut remote) = pair();
+        remote.send(&Message::Error(ErrorCode::ServerFull, "Full".into())).unwrap();
+        let hello = Message::ClientHello(ProtocolVersion::current());
+        let err = client().handshake(Box::new(local), &hello).unwrap_err();
+        let err = err.downcast_ref::<ServerError>().unwrap();
+        assert_eq!(err.code, ErrorCode::ServerFull);
     }
 }
@@ -0,0 +1,113 @@
+//! Prediction of the own move commands.
+//!
+//! Commands take a round trip until their effect shows up in a snapshot. To
+//! let the own units respond instantly, the client applies its move commands
+//! locally until the server reports them as processed.
+
+use std::collections::VecDeque;
+
+use network::Command;
+use state::Player;
+
+pub struct Prediction {
+    /// Sequence number of the next command
+    next_sequence: u64,
+    /// Commands the server has not processed yet, oldest first
+    pending: VecDeque<(u64, Command)>,
+}
+
+impl Prediction {
+    pub fn new() -> Prediction {
+        Prediction {
+            next_sequence: 1,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Assign the next sequence number to the command and remember it until
+    /// the server has processed it.
+    pub fn issue(&mut self, command: Command) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.pending.push_back((sequence, command));
+        sequence
+    }
+
+    /// Forget the commands up to the last one processed by the server.
+    pub fn reconcile(&mut self, processed: u64) {
+        while self.pending.front().map_or(false, |&(sequence, _)| sequence <= processed) {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Return the number of commands the server has not processed yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Predict the own player `dt_ms` after its authoritative state.
+    ///
+    /// Units are steered with the `speed` of the match.
+    pub fn predict(&self, player: &Player, dt_ms: f64, speed: f64) -> Player {
+        let mut player = player.clone();
+        // Later commands override earlier ones for the same unit
+        for &(_, ref command) in self.pending.iter() {
+            if let Command::Move(id, target) = *command {
+                if let Some(unit) = player.unit_mut(id) {
+                    unit.face(target);
+                    unit.steer(target, speed);
+                }
+            }
+        }
+        for unit in player.units.iter_mut() {
+            unit.update(dt_ms);
+        }
+        player
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Prediction;
+    use network::Command;
+    use settings::DEFAULT_UNIT_HEALTH;
+    use state::{Player, Unit, Faction};
+
+    fn player() -> Player {
+        let mut player = Player::new(0, Faction::Rock);
+        player.units.push(Unit::new(0, [100.0, 100.0], DEFAULT_UNIT_HEALTH));
+        player.units.push(Unit::new(1, [200.0, 100.0], DEFAULT_UNIT_HEALTH));
+        player
+    }
+
+    #[test]
+    fn test_predict_move() {
+        let mut prediction = Prediction::new();
+        assert_eq!(prediction.issue(Command::Move(0.into(), [100.0, 300.0])), 1);
+
+        let predicted = prediction.predict(&player(), 10.0, 0.0001);
+        let unit = predicted.unit(0.into()).unwrap();
+        assert!(unit.position[1] > 100.0);
+        assert_eq!(unit.position[0], 100.0);
+        // Other units are not affected
+        assert_eq!(predicted.unit(1.into()).unwrap().position, [200.0, 100.0]);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let mut prediction = Prediction::new();
+        prediction.issue(Command::Move(0.into(), [100.0, 300.0]));
+        prediction.issue(Command::Split(0.into()));
+        prediction.issue(Command::Move(1.into(), [100.0, 300.0]));
+
+        prediction.reconcile(2);
+        assert_eq!(prediction.pending(), 1);
+        // The processed move is part of the authoritative state now
+        let predicted = prediction.predict(&player(), 10.0, 0.0001);
+        assert_eq!(predicted.unit(0.into()).unwrap().position, [100.0, 100.0]);
+        assert!(predicted.unit(1.into()).unwrap().position[0] < 200.0);
+
+        prediction.reconcile(3);
+        assert_eq!(prediction.pending(), 0);
+    }
+}
@@ -0,0 +1,152 @@
+//! The servers the player joined recently.
+//!
+//! They are stored in a text file with one `host:port` address per line, the
+//! most recent one first.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Number of servers that are remembered
+pub const MAX_RECENT: usize = 5;
+
+/// Name of the file in the home directory of the user
+const FILE_NAME: &'static str = ".rpsrtsrs_servers";
+
+/// Address of a server as entered by the player.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Address {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Address, String> {
+        let mut parts = s.rsplitn(2, ':');
+        let port = parts.next().unwrap_or("");
+        let host = parts.next().unwrap_or("");
+        if host.is_empty() {
+            return Err(format!("Missing host in address: {}", s));
+        }
+        match port.parse() {
+            Ok(port) => Ok(Address { host: host.to_string(), port: port }),
+            Err(_) => Err(format!("Invalid port in address: {}", s)),
+        }
+    }
+}
+
+/// Return the file in the home directory, or in the working directory if
+/// there is none.
+pub fn default_path() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(FILE_NAME),
+        None => PathBuf::from(FILE_NAME),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecentServers {
+    /// File the servers are saved to, if any
+    path: Option<PathBuf>,
+    /// The most recent server first
+    pub addresses: Vec<Address>,
+}
+
+impl RecentServers {
+    /// Remember servers without saving them.
+    pub fn new() -> RecentServers {
+        RecentServers {
+            path: None,
+            addresses: vec![],
+        }
+    }
+
+    /// Read the servers from the file, which is created when saving.
+    ///
+    /// Lines that are not valid addresses are skipped.
+    pub fn load<P: AsRef<Path>>(path: P) -> RecentServers {
+        let mut content = String::new();
+        if let Ok(mut file) = File::open(path.as_ref()) {
+            if let Err(e) = file.read_to_string(&mut content) {
+                println!("Could not read {}: {}", path.as_ref().display(), e);
+            }
+        }
+        RecentServers {
+            path: Some(path.as_ref().to_path_buf()),
+            addresses: content.lines()
+                .filter_map(|line| line.trim().parse().ok())
+                .take(MAX_RECENT)
+                .collect(),
+        }
+    }
+
+    /// Move the server to the front, forgetting the oldest one if there are too many.
+    pub fn add(&mut self, address: Address) {
+        self.addresses.retain(|other| *other != address);
+        self.addresses.insert(0, address);
+        self.addresses.truncate(MAX_RECENT);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut file = File::create(path)?;
+        for address in self.addresses.iter() {
+            writeln!(file, "{}", address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use rand;
+
+    use super::{Address, RecentServers, MAX_RECENT};
+
+    fn address(port: u16) -> Address {
+        Address { host: "example.com".into(), port: port }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("example.com:8080".parse(), Ok(address(8080)));
+        assert_eq!(address(8080).to_string(), "example.com:8080");
+        assert!("example.com".parse::<Address>().is_err());
+        assert!(":8080".parse::<Address>().is_err());
+        assert!("example.com:http".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir().join(format!("rpsrtsrs_servers_{}", rand::random::<u32>()));
+        let mut recent = RecentServers::load(&path);
+        assert!(recent.addresses.is_empty());
+        for port in 0..MAX_RECENT as u16 + 1 {
+            recent.add(address(port));
+        }
+        recent.add(address(3));
+        recent.save().unwrap();
+
+        let loaded = RecentServers::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, recent);
+        assert_eq!(loaded.addresses, vec![address(3), address(5), address(4), address(2), address(1)]);
+    }
+}
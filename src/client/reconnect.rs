@@ -0,0 +1,99 @@
+//! Reconnecting after the connection to the server was lost.
+//!
+//! The attempts are spaced with an exponential backoff, so that a server
+//! that is restarting is not flooded with connections.
+
+use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
+
+use piston::input::RenderArgs;
+
+use colors::{SHADOW, YELLOW, ORANGE};
+
+/// Time in ms before the first attempt
+const INITIAL_DELAY_MS: f64 = 500.0;
+
+/// Maximal time in ms between two attempts
+const MAX_DELAY_MS: f64 = 8000.0;
+
+/// Number of attempts before giving up
+pub const MAX_ATTEMPTS: u32 = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reconnect {
+    /// Number of the next attempt, starting at 1
+    pub attempt: u32,
+    /// Time in ms between the previous and the next attempt
+    delay: f64,
+    /// Time in ms until the next attempt
+    remaining: f64,
+}
+
+impl Reconnect {
+    pub fn new() -> Reconnect {
+        Reconnect {
+            attempt: 1,
+            delay: INITIAL_DELAY_MS,
+            remaining: INITIAL_DELAY_MS,
+        }
+    }
+
+    /// Advance by the elapsed time and return whether the next attempt is due.
+    pub fn update(&mut self, dt_ms: f64) -> bool {
+        self.remaining -= dt_ms;
+        self.remaining <= 0.0
+    }
+
+    /// Schedule the attempt after a failed one, or give up.
+    pub fn retry(&self) -> Option<Reconnect> {
+        if self.attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        let delay = (self.delay * 2.0).min(MAX_DELAY_MS);
+        Some(Reconnect {
+            attempt: self.attempt + 1,
+            delay: delay,
+            remaining: delay,
+        })
+    }
+
+    /// Draw the overlay on top of the game.
+    pub fn render(&self, args: &RenderArgs, gl: &mut GlGraphics, cache: &mut GlyphCache) {
+        use graphics::{Text, rectangle, Transformed};
+        let text = Text::new_color(YELLOW, 64);
+        let details = Text::new_color(ORANGE, 24);
+        let status = if self.remaining > 0.0 {
+            format!("Attempt {} of {} in {:.0} s", self.attempt, MAX_ATTEMPTS, (self.remaining / 1000.0).ceil())
+        } else {
+            format!("Attempt {} of {}", self.attempt, MAX_ATTEMPTS)
+        };
+        gl.draw(args.viewport(), |c, gl| {
+            rectangle(SHADOW, [0.0, 0.0, args.width as f64, args.height as f64], c.transform, gl);
+            let transform = c.transform.trans(0.0, 100.0);
+            text.draw("Reconnecting", cache, &c.draw_state, transform, gl);
+            details.draw(&status, cache, &c.draw_state, transform.trans(0.0, 50.0), gl);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reconnect, MAX_ATTEMPTS};
+
+    #[test]
+    fn test_backoff() {
+        let mut reconnect = Reconnect::new();
+        assert!(!reconnect.update(400.0));
+        assert!(reconnect.update(100.0));
+
+        let mut delays = vec![];
+        while let Some(next) = reconnect.retry() {
+            delays.push(next.delay);
+            reconnect = next;
+        }
+        assert_eq!(reconnect.attempt, MAX_ATTEMPTS);
+        assert_eq!(delays, vec![1000.0, 2000.0, 4000.0, 8000.0, 8000.0, 8000.0, 8000.0]);
+        assert!(!reconnect.update(7999.0));
+        assert!(reconnect.update(1.0));
+    }
+}
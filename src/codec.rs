@@ -0,0 +1,198 @@
+//! Framing of messages on a byte stream.
+//!
+//! Every frame on the wire consists of the length of the payload as a
+//! big-endian `u32`, followed by the payload: a bincode serialized `Message`.
+//! This is used by the server and the clients alike.
+//!
+//! The `ClientHello` and `VersionMismatch` messages are the exception: they
+//! are sent without a length, as by the first protocol version, so that peers
+//! of every version can negotiate. They start with their variant index as a
+//! little-endian `u32`, which is never a valid length: 0 is an empty payload
+//! and 1 << 24 exceeds `MAX_FRAME_SIZE`.
+
+use std::error;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+
+use bincode::{self, serialize, deserialize, Infinite};
+
+use network::Message;
+
+/// Maximum size of the payload of a frame in bytes
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// Size of the unframed `ClientHello` and `VersionMismatch` in bytes
+const HANDSHAKE_SIZE: usize = 12;
+
+/// Errors that can occur while reading or writing frames.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the stream failed
+    Io(io::Error),
+    /// The payload could not be (de)serialized
+    Encoding(bincode::Error),
+    /// The payload exceeds `MAX_FRAME_SIZE`
+    FrameTooLarge(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Encoding(ref e) => write!(f, "Encoding error: {}", e),
+            Error::FrameTooLarge(size) => write!(f, "Frame of {} bytes exceeds the maximum of {} bytes",
+                                                 size, MAX_FRAME_SIZE),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error",
+            Error::Encoding(_) => "encoding error",
+            Error::FrameTooLarge(_) => "frame too large",
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Error {
+        Error::Encoding(e)
+    }
+}
+
+/// Serialize the message into a complete frame.
+///
+/// This allows sending the same frame to several peers without serializing
+/// the message again.
+pub fn encode(message: &Message) -> Result<Vec<u8>, Error> {
+    let payload = serialize(message, Infinite)?;
+    if is_handshake(&payload) {
+        return Ok(payload);
+    }
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(payload.len()));
+    }
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Write the message as a single frame.
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), Error> {
+    let frame = encode(message)?;
+    writer.write_all(&frame)?;
+    Ok(())
+}
+
+/// Block until a complete frame has been read and return the contained message.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, Error> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header)?;
+    if is_handshake(&header) {
+        let mut message = [0u8; HANDSHAKE_SIZE];
+        message[..4].copy_from_slice(&header);
+        reader.read_exact(&mut message[4..])?;
+        return Ok(deserialize(&message)?);
+    }
+    let len = header.iter().fold(0usize, |len, &byte| len << 8 | byte as usize);
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(len));
+    }
+    // The buffer grows with the data that actually arrives, a peer cannot
+    // make us allocate the full size by sending a large length only
+    let mut payload = vec![];
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "The frame is truncated").into());
+    }
+    Ok(deserialize(&payload)?)
+}
+
+/// Return the serialized message in a frame returned by `encode`.
+pub fn payload(frame: &[u8]) -> &[u8] {
+    if is_handshake(frame) {
+        frame
+    } else {
+        &frame[4..]
+    }
+}
+
+/// Return whether the data starts with the variant index of `ClientHello` or
+/// `VersionMismatch`, which are sent unframed.
+fn is_handshake(data: &[u8]) -> bool {
+    data.starts_with(&[0, 0, 0, 0]) || data.starts_with(&[1, 0, 0, 0])
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{encode, payload, read_message, write_message, Error, MAX_FRAME_SIZE};
+    use network::{Message, ProtocolVersion};
+    use state::GameState;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &Message::ClientHello(ProtocolVersion::current())).unwrap();
+        write_message(&mut buffer, &Message::UpdateGamestate(1, GameState::new())).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader).unwrap(), Message::ClientHello(ProtocolVersion::current()));
+        assert_eq!(read_message(&mut reader).unwrap(), Message::UpdateGamestate(1, GameState::new()));
+        match read_message(&mut reader) {
+            Err(Error::Io(_)) => {}
+            other => panic!("Expected end of stream, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_length_prefix() {
+        let frame = encode(&Message::Ack(1)).unwrap();
+        assert_eq!(frame.len(), 16);
+        assert_eq!(frame[..4], [0, 0, 0, 12]);
+        assert_eq!(payload(&frame), &frame[4..]);
+    }
+
+    #[test]
+    fn test_unframed_handshake() {
+        // As sent by peers of the first protocol version
+        let version = ProtocolVersion { version: 1, features: 0 };
+        let hello = vec![0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(encode(&Message::ClientHello(version)).unwrap(), hello);
+        assert_eq!(payload(&hello), &hello[..]);
+        assert_eq!(read_message(&mut Cursor::new(hello)).unwrap(), Message::ClientHello(version));
+
+        let mismatch = encode(&Message::VersionMismatch(ProtocolVersion::current())).unwrap();
+        assert_eq!(mismatch[..4], [1, 0, 0, 0]);
+        assert_eq!(read_message(&mut Cursor::new(mismatch)).unwrap(),
+                   Message::VersionMismatch(ProtocolVersion::current()));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let len = MAX_FRAME_SIZE as u32 + 1;
+        let header = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        match read_message(&mut Cursor::new(header)) {
+            Err(Error::FrameTooLarge(size)) => assert_eq!(size, MAX_FRAME_SIZE + 1),
+            other => panic!("Expected too large frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut frame = encode(&Message::UpdateGamestate(1, GameState::new())).unwrap();
+        frame.pop();
+        assert!(read_message(&mut Cursor::new(frame)).is_err());
+    }
+}
@@ -13,6 +13,7 @@ pub const ORANGE:[f32; 4] = [1.0, 0.61, 0.22, 1.0];
 pub const RED:[f32; 4] = [1.0, 0.22, 0.22, 1.0];
 pub const LIGHT_BLUE:[f32; 4] = [0.22, 0.22, 1.0, 1.0];
 pub const BLUE:[f32; 4] = [0.0, 0.0, 1.0, 1.0];
+pub const SHADOW:[f32; 4] = [0.0, 0.0, 0.0, 0.7];
 
 pub struct Player {
     pub primary: [f32; 4],
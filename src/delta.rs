@@ -0,0 +1,191 @@
+//! Delta compression of game states.
+//!
+//! Instead of the whole `GameState`, the server sends the difference to the
+//! last snapshot the client acknowledged. Only units and buildings that are new
+//! or have changed are transferred, together with the IDs of the removed ones.
+
+use state::{GameState, Player, PlayerStatus, Unit, Building, Projectile, DamageModel, Faction,
+            ClientId, UnitId, BuildingId};
+
+/// Number of snapshots kept as possible baselines
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+/// The changes of a player since the baseline.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PlayerDelta {
+    pub id: ClientId,
+    pub faction: Faction,
+    pub eliminated: bool,
+    pub last_command: u64,
+    pub status: PlayerStatus,
+    /// Units that are new or have changed
+    pub units: Vec<Unit>,
+    pub removed_units: Vec<UnitId>,
+    /// Buildings that are new or have changed
+    pub buildings: Vec<Building>,
+    pub removed_buildings: Vec<BuildingId>,
+}
+
+/// The changes of a `GameState` since a baseline snapshot.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Delta {
+    /// Tick of the snapshot the delta is based on
+    pub baseline: u64,
+    /// Players that are new or have changed
+    pub players: Vec<PlayerDelta>,
+    pub removed_players: Vec<ClientId>,
+    /// Projectiles are short-lived, they are always transferred completely
+    pub projectiles: Vec<Projectile>,
+    pub damage_model: DamageModel,
+}
+
+impl Delta {
+    /// Compute the changes from the snapshot `baseline` of tick `tick` to `current`.
+    pub fn between(tick: u64, baseline: &GameState, current: &GameState) -> Delta {
+        let mut players = vec![];
+        for player in current.players.iter() {
+            let empty = Player::new(player.id, player.faction);
+            let old = baseline.player(player.id).unwrap_or(&empty);
+            let (units, removed_units) = diff(&old.units, &player.units, |unit| unit.id);
+            let (buildings, removed_buildings) = diff(&old.buildings, &player.buildings,
+                                                      |building| building.id);
+            let unchanged = baseline.player(player.id).is_some()
+                && old.eliminated == player.eliminated
+                && old.last_command == player.last_command
+                && old.status == player.status
+                && units.is_empty() && removed_units.is_empty()
+                && buildings.is_empty() && removed_buildings.is_empty();
+            if !unchanged {
+                players.push(PlayerDelta {
+                    id: player.id,
+                    faction: player.faction,
+                    eliminated: player.eliminated,
+                    last_command: player.last_command,
+                    status: player.status,
+                    units: units,
+                    removed_units: removed_units,
+                    buildings: buildings,
+                    removed_buildings: removed_buildings,
+                });
+            }
+        }
+        let removed_players = baseline.players.iter()
+            .filter(|player| current.player(player.id).is_none())
+            .map(|player| player.id)
+            .collect();
+
+        Delta {
+            baseline: tick,
+            players: players,
+            removed_players: removed_players,
+            projectiles: current.projectiles.clone(),
+            damage_model: current.damage_model,
+        }
+    }
+
+    /// Reconstruct the game state from the baseline snapshot.
+    ///
+    /// Changed entries keep their position, new entries are appended.
+    pub fn apply(&self, baseline: &GameState) -> GameState {
+        let mut game = baseline.clone();
+        game.players.retain(|player| !self.removed_players.contains(&player.id));
+        for delta in self.players.iter() {
+            let index = match game.players.iter().position(|player| player.id == delta.id) {
+                Some(index) => index,
+                None => {
+                    game.players.push(Player::new(delta.id, delta.faction));
+                    game.players.len() - 1
+                }
+            };
+            let player = &mut game.players[index];
+            player.faction = delta.faction;
+            player.eliminated = delta.eliminated;
+            player.last_command = delta.last_command;
+            player.status = delta.status;
+            player.units = patch(&player.units, &delta.units, &delta.removed_units, |unit| unit.id);
+            player.buildings = patch(&player.buildings, &delta.buildings, &delta.removed_buildings,
+                                     |building| building.id);
+        }
+        game.projectiles = self.projectiles.clone();
+        game.damage_model = self.damage_model;
+        game
+    }
+}
+
+/// Return the entries of `current` that are new or differ from `baseline` and
+/// the IDs of the entries that have been removed.
+fn diff<T, I, F>(baseline: &[T], current: &[T], id: F) -> (Vec<T>, Vec<I>)
+    where T: Clone + PartialEq, I: PartialEq, F: Fn(&T) -> I
+{
+    let changed = current.iter()
+        .filter(|entry| !baseline.iter().any(|old| old == *entry))
+        .cloned()
+        .collect();
+    let removed = baseline.iter()
+        .filter(|old| !current.iter().any(|entry| id(entry) == id(old)))
+        .map(|old| id(old))
+        .collect();
+    (changed, removed)
+}
+
+/// Apply the changes returned by `diff` to `baseline`.
+fn patch<T, I, F>(baseline: &[T], changed: &[T], removed: &[I], id: F) -> Vec<T>
+    where T: Clone, I: PartialEq, F: Fn(&T) -> I
+{
+    let mut entries: Vec<T> = baseline.iter()
+        .filter(|old| !removed.contains(&id(old)))
+        .cloned()
+        .collect();
+    for entry in changed.iter() {
+        match entries.iter().position(|old| id(old) == id(entry)) {
+            Some(index) => entries[index] = entry.clone(),
+            None => entries.push(entry.clone()),
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::Delta;
+    use settings::DEFAULT_UNIT_HEALTH;
+    use state::{Player, Unit, Faction, two_players};
+
+    #[test]
+    fn test_unchanged() {
+        let game = two_players();
+        let delta = Delta::between(3, &game, &game);
+        assert_eq!(delta.baseline, 3);
+        assert!(delta.players.is_empty());
+        assert!(delta.removed_players.is_empty());
+        assert_eq!(delta.apply(&game), game);
+    }
+
+    #[test]
+    fn test_changed_units_only() {
+        let baseline = two_players();
+        let mut current = baseline.clone();
+        current.players[1].units[0].position = [120.0, 320.0];
+
+        let delta = Delta::between(0, &baseline, &current);
+        assert_eq!(delta.players.len(), 1);
+        assert_eq!(delta.players[0].units, vec![current.players[1].units[0].clone()]);
+        assert_eq!(delta.apply(&baseline), current);
+    }
+
+    #[test]
+    fn test_added_and_removed() {
+        let baseline = two_players();
+        let mut current = baseline.clone();
+        current.players[1].merge(2.into(), 3.into(), 0.into());
+        current.players.remove(0);
+        let mut player = Player::new(7, Faction::Paper);
+        player.units.push(Unit::new(8, [10.0, 10.0], DEFAULT_UNIT_HEALTH));
+        current.players.push(player);
+
+        let delta = Delta::between(0, &baseline, &current);
+        assert_eq!(delta.removed_players, vec![0.into()]);
+        assert_eq!(delta.players[0].removed_units, vec![2.into(), 3.into()]);
+        assert_eq!(delta.apply(&baseline), current);
+    }
+}
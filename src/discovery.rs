@@ -0,0 +1,153 @@
+//! Discovery of servers in the local network.
+//!
+//! Clients broadcast a `Probe` datagram to `DISCOVERY_PORT`, servers that
+//! announce themselves answer with an `Announcement` describing the match.
+//! Both are bincode serialized `Discovery` packets. Datagrams that are not
+//! valid packets are ignored.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use bincode::{serialize, deserialize, Infinite};
+
+use network::{Phase, ProtocolVersion};
+use transport::Transport;
+
+/// Well-known port the servers listen for probes on
+pub const DISCOVERY_PORT: u16 = 8079;
+
+/// Identifies probes of this game
+pub const DISCOVERY_MAGIC: u32 = 0x7270_7372;
+
+/// Interval in ms in which the server checks whether to stop answering
+const POLL_MS: u64 = 100;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum Discovery {
+    /// Broadcast by clients, contains `DISCOVERY_MAGIC`
+    Probe(u32),
+    /// The answer of a server
+    Announcement(ServerInfo),
+}
+
+/// A server as announced in the local network.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ServerInfo {
+    /// Protocol the server speaks, clients need a compatible one to join
+    pub version: ProtocolVersion,
+    pub name: String,
+    /// Port the clients connect to
+    pub port: u16,
+    /// Transport the clients connect over
+    pub transport: Transport,
+    pub players: u32,
+    pub max_players: u32,
+    pub phase: Phase,
+}
+
+/// Answer the probes received on the socket with the current `ServerInfo`
+/// until `shutdown` is set.
+pub fn answer<F: Fn() -> ServerInfo>(socket: &UdpSocket, info: F, shutdown: &AtomicBool) -> io::Result<()> {
+    socket.set_read_timeout(Some(Duration::from_millis(POLL_MS)))?;
+    let mut buffer = [0u8; 512];
+    while !shutdown.load(Ordering::SeqCst) {
+        let (len, addr) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        match deserialize(&buffer[..len]) {
+            Ok(Discovery::Probe(DISCOVERY_MAGIC)) => {
+                let announcement = serialize(&Discovery::Announcement(info()), Infinite).unwrap();
+                if let Err(e) = socket.send_to(&announcement, addr) {
+                    println!("Could not answer probe of {}: {}", addr, e);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Broadcast a probe in the local network and return the servers that answered
+/// within the timeout, with the address to connect to.
+pub fn discover(timeout: Duration) -> io::Result<Vec<(SocketAddr, ServerInfo)>> {
+    let broadcast = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), DISCOVERY_PORT));
+    discover_at(broadcast, timeout)
+}
+
+/// Send a probe to the given address and collect the answers.
+pub fn discover_at(target: SocketAddr, timeout: Duration) -> io::Result<Vec<(SocketAddr, ServerInfo)>> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.set_broadcast(true)?;
+    let probe = serialize(&Discovery::Probe(DISCOVERY_MAGIC), Infinite).unwrap();
+    socket.send_to(&probe, target)?;
+
+    // A server may be reachable over several interfaces
+    let mut servers: HashMap<SocketAddr, ServerInfo> = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; 512];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, addr) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        };
+        if let Ok(Discovery::Announcement(info)) = deserialize(&buffer[..len]) {
+            servers.insert(SocketAddr::new(addr.ip(), info.port), info);
+        }
+    }
+    let mut servers: Vec<(SocketAddr, ServerInfo)> = servers.into_iter().collect();
+    servers.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{answer, discover_at, ServerInfo};
+    use network::{Phase, ProtocolVersion};
+    use transport::Transport;
+
+    fn info() -> ServerInfo {
+        ServerInfo {
+            version: ProtocolVersion::current(),
+            name: "test".into(),
+            port: 8080,
+            transport: Transport::Udp,
+            players: 2,
+            max_players: 8,
+            phase: Phase::Lobby,
+        }
+    }
+
+    #[test]
+    fn test_discover() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_clone = shutdown.clone();
+        let server = thread::spawn(move || answer(&socket, info, &shutdown_clone).unwrap());
+
+        // Not a probe
+        UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&[1, 2, 3], addr).unwrap();
+
+        let servers = discover_at(addr, Duration::from_millis(500)).unwrap();
+        assert_eq!(servers, vec![("127.0.0.1:8080".parse().unwrap(), info())]);
+        shutdown.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+}
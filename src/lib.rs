@@ -6,13 +6,21 @@ extern crate piston;
 extern crate graphics;
 extern crate opengl_graphics;
 extern crate rand;
+extern crate toml;
 #[cfg(feature = "include_sdl2")] extern crate sdl2_window;
 #[cfg(feature = "include_glfw")] extern crate glfw_window;
 #[cfg(feature = "include_glutin")] extern crate glutin_window;
 
 pub mod shapes;
 pub mod state;
+pub mod settings;
 pub mod network;
+pub mod codec;
+pub mod delta;
+pub mod udp;
+pub mod transport;
+pub mod simulator;
+pub mod discovery;
 pub mod colors;
 pub mod server;
 pub mod client;
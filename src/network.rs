@@ -3,27 +3,381 @@
 //! Everything related to the network protocol between the sever and the
 //! clients.
 
-use state::{GameState, WorldState, UnitId, ClientId};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::{OsRng, Rng};
+
+use settings::GameSettings;
+use state::{GameState, UnitId, BuildingId, ClientId, Faction};
+use delta::Delta;
+
+/// Version of the network protocol.
+///
+/// Increase it whenever the serialized form of a message changes, so that
+/// incompatible clients and servers refuse each other during the handshake.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Duration of a server tick in ms
+pub const TICK_MS: u64 = 10;
+
+/// Interval in ms at which the peers measure the round trip time
+pub const PING_INTERVAL_MS: u64 = 1000;
+
+/// Time in ms after which the server disconnects a silent client
+pub const IDLE_TIMEOUT_MS: u64 = 5000;
+
+/// The peer understands `DeltaGamestate` messages
+pub const FEATURE_DELTA: u32 = 1 << 0;
+
+/// Bitset of the optional protocol features supported by this build.
+pub const PROTOCOL_FEATURES: u32 = FEATURE_DELTA;
+
+/// The protocol version and features spoken by a client or server.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub struct ProtocolVersion {
+    pub version: u32,
+    pub features: u32,
+}
+
+impl ProtocolVersion {
+    /// Return the protocol version of this build.
+    pub fn current() -> ProtocolVersion {
+        ProtocolVersion {
+            version: PROTOCOL_VERSION,
+            features: PROTOCOL_FEATURES,
+        }
+    }
+
+    /// Return whether a peer speaking `other` can talk to us.
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.version == other.version
+    }
+
+    /// Return the protocol used with a compatible peer speaking `other`.
+    ///
+    /// Only features supported by both sides are enabled.
+    pub fn negotiate(&self, other: &ProtocolVersion) -> ProtocolVersion {
+        ProtocolVersion {
+            version: self.version,
+            features: self.features & other.features,
+        }
+    }
+
+    /// Return whether the specified feature flag is enabled.
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} (features {:#x})", self.version, self.features)
+    }
+}
+
+/// A secret that authenticates a client when it reconnects.
+///
+/// The token is handed out in the `ServerHello` message and must be presented
+/// together with the `ClientId` in a `ClientReconnect` message.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone)]
+pub struct SessionToken(pub [u8; 16]);
+
+impl SessionToken {
+    /// Generate a new random token.
+    pub fn generate() -> SessionToken {
+        let mut rng = OsRng::new().expect("Could not access the random number generator of the OS");
+        let mut token = [0u8; 16];
+        rng.fill_bytes(&mut token);
+        SessionToken(token)
+    }
+}
+
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Don't leak the secret into logs.
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SessionToken(..)")
+    }
+}
+
+impl FromStr for SessionToken {
+    type Err = String;
+
+    /// Parse a token from its hexadecimal representation.
+    fn from_str(s: &str) -> Result<SessionToken, String> {
+        let mut token = [0u8; 16];
+        if s.len() != 2 * token.len() || !s.chars().all(|c| c.is_digit(16)) {
+            return Err(format!("A session token consists of {} hex digits", 2 * token.len()));
+        }
+        for (i, byte) in token.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        Ok(SessionToken(token))
+    }
+}
 
 /// Commands alter the game state.
 ///
 /// A command is sent from the client to the server. Examples include the
 /// movement of a unit or the decision to attack another unit.
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum Command {
     /// Move command with unit ID and target
     Move(UnitId, [f64; 2]),
+    /// Merge two adjacent units into a building
+    Merge(UnitId, UnitId),
+    /// Split a building back into its units
+    Split(BuildingId),
+}
+
+/// Reason codes of a `Message::Error`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ErrorCode {
+    /// There is no player for the client ID presented when reconnecting
+    UnknownClient,
+    /// The session token presented when reconnecting is invalid or stale
+    InvalidToken,
+    /// A message could not be decoded
+    ProtocolMismatch,
+    /// The message is not expected in the current state of the connection
+    UnexpectedMessage,
+    /// The command is not valid in the current game state
+    InvalidCommand,
+    /// The command refers to a unit or building of another player
+    UnauthorizedUnit,
+    /// The maximum number of players has been reached
+    ServerFull,
+    /// The match has already started, only reconnects are accepted
+    GameAlreadyRunning,
+    /// The match is over, no more players are accepted
+    MatchFinished,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let description = match *self {
+            ErrorCode::UnknownClient => "Unknown client",
+            ErrorCode::InvalidToken => "Invalid session token",
+            ErrorCode::ProtocolMismatch => "Protocol mismatch",
+            ErrorCode::UnexpectedMessage => "Unexpected message",
+            ErrorCode::InvalidCommand => "Invalid command",
+            ErrorCode::UnauthorizedUnit => "Unauthorized unit",
+            ErrorCode::ServerFull => "Server full",
+            ErrorCode::GameAlreadyRunning => "Game already running",
+            ErrorCode::MatchFinished => "Match finished",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Reasons for the server to reject a `Command`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum CommandError {
+    /// The sender is not a player of the current match
+    UnknownPlayer(ClientId),
+    /// There is no unit with this ID
+    UnknownUnit(UnitId),
+    /// The unit belongs to another player
+    ForeignUnit(UnitId),
+    /// There is no building with this ID
+    UnknownBuilding(BuildingId),
+    /// The building belongs to another player
+    ForeignBuilding(BuildingId),
+    /// A unit cannot be merged with itself
+    SameUnit(UnitId),
+    /// The units are too far apart to be merged
+    NotAdjacent(UnitId, UnitId),
+}
+
+impl CommandError {
+    /// Return the reason code sent to the client.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            CommandError::UnknownPlayer(_) |
+            CommandError::ForeignUnit(_) |
+            CommandError::ForeignBuilding(_) => ErrorCode::UnauthorizedUnit,
+            _ => ErrorCode::InvalidCommand,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CommandError::UnknownPlayer(id) => write!(f, "Client {} is not a player", id),
+            CommandError::UnknownUnit(id) => write!(f, "Unit {} does not exist", id),
+            CommandError::ForeignUnit(id) => write!(f, "Unit {} belongs to another player", id),
+            CommandError::UnknownBuilding(id) => write!(f, "Building {} does not exist", id),
+            CommandError::ForeignBuilding(id) => write!(f, "Building {} belongs to another player", id),
+            CommandError::SameUnit(id) => write!(f, "Unit {} cannot be merged with itself", id),
+            CommandError::NotAdjacent(a, b) => write!(f, "Units {} and {} are too far apart", a, b),
+        }
+    }
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        "command rejected"
+    }
+}
+
+/// The outcome of a finished match for a single player.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub enum Outcome {
+    Victory,
+    Defeat,
+    /// The last remaining players have been eliminated at the same time
+    Draw,
+}
+
+/// The result of a finished match for a single player.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PlayerResult {
+    pub id: ClientId,
+    pub outcome: Outcome,
+    /// Final placement, starting at 1 for the winner
+    pub rank: u32,
+}
+
+/// The phases of a match.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub enum Phase {
+    /// Players may join and get ready
+    Lobby,
+    /// Everybody is ready, the match starts in the given number of seconds
+    Countdown(u64),
+    /// Only players of the match may reconnect
+    Running,
+    Finished,
+}
+
+/// A player as shown in the lobby.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct LobbyPlayer {
+    pub id: ClientId,
+    pub faction: Faction,
+    pub ready: bool,
 }
 
 /// Primary message type sent between server and client.
 ///
 /// This includes connection buildup and game state transfer.
+///
+/// The first two variants are used to negotiate the protocol version. Never
+/// change their position or content, so that peers of every version can decode
+/// them.
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum Message {
-    Error,
-    ClientHello,
-    ClientReconnect(ClientId),
-    ServerHello(ClientId, WorldState),
-    UpdateGamestate(GameState),
-    Command(Command),
+    ClientHello(ProtocolVersion),
+    /// The server refuses the client because it speaks another protocol version
+    VersionMismatch(ProtocolVersion),
+    /// Something went wrong, with a reason code and a human-readable description
+    Error(ErrorCode, String),
+    ClientReconnect(ProtocolVersion, ClientId, SessionToken),
+    /// Accepts the client, with the settings of the match
+    ServerHello(ProtocolVersion, ClientId, SessionToken, GameSettings),
+    /// Snapshot of the game at the given server tick
+    UpdateGamestate(u64, GameState),
+    /// Snapshot of the game at the given server tick, relative to a snapshot
+    /// the client has acknowledged
+    DeltaGamestate(u64, Delta),
+    /// The client has received the snapshot of the given tick
+    Ack(u64),
+    /// A command with its sequence number, which starts at 1 for every client
+    Command(u64, Command),
+    GameOver(Vec<PlayerResult>),
+    /// Asks the peer to send the timestamp in ms back
+    Ping(u64),
+    /// Answer to a `Ping`, with its timestamp
+    Pong(u64),
+    /// The connection of the player was lost
+    PlayerLeft(ClientId),
+    /// The phase of the match and the players who joined it
+    Lobby(Phase, Vec<LobbyPlayer>),
+    /// The player is ready to start the match, or not anymore
+    Ready(bool),
+}
+
+impl Message {
+    /// Return whether the message must be delivered.
+    ///
+    /// Snapshots and their acknowledgements are superseded by newer ones, so
+    /// a lost one does not need to be sent again.
+    pub fn is_reliable(&self) -> bool {
+        match *self {
+            Message::UpdateGamestate(..) | Message::DeltaGamestate(..) | Message::Ack(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Return the duration in whole ms.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use bincode::{serialize, deserialize, Infinite};
+
+    use super::{SessionToken, ProtocolVersion, Message, CommandError, ErrorCode};
+
+    #[test]
+    fn test_protocol_version() {
+        let a = ProtocolVersion { version: 3, features: 0b101 };
+        let b = ProtocolVersion { version: 3, features: 0b110 };
+        let c = ProtocolVersion { version: 4, features: 0b101 };
+        assert!(a.is_compatible(&b));
+        assert!(!a.is_compatible(&c));
+        assert_eq!(a.negotiate(&b), ProtocolVersion { version: 3, features: 0b100 });
+        assert!(a.has_feature(0b001));
+        assert!(!a.has_feature(0b011));
+    }
+
+    #[test]
+    fn test_handshake_encoding_is_stable() {
+        // Peers of every version must be able to decode the handshake
+        let version = ProtocolVersion { version: 7, features: 1 };
+        let hello = serialize(&Message::ClientHello(version), Infinite).unwrap();
+        assert_eq!(hello, vec![0, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0]);
+        let mismatch = serialize(&Message::VersionMismatch(version), Infinite).unwrap();
+        assert_eq!(deserialize::<Message>(&mismatch).unwrap(), Message::VersionMismatch(version));
+        assert_eq!(mismatch[..4], [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_command_error_code() {
+        assert_eq!(CommandError::ForeignUnit(1.into()).code(), ErrorCode::UnauthorizedUnit);
+        assert_eq!(CommandError::ForeignBuilding(1.into()).code(), ErrorCode::UnauthorizedUnit);
+        assert_eq!(CommandError::UnknownUnit(1.into()).code(), ErrorCode::InvalidCommand);
+        assert_eq!(CommandError::NotAdjacent(1.into(), 2.into()).code(), ErrorCode::InvalidCommand);
+
+        let error = Message::Error(ErrorCode::ServerFull, "The match already has 8 players".into());
+        let encoded = serialize(&error, Infinite).unwrap();
+        assert_eq!(deserialize::<Message>(&encoded).unwrap(), error);
+    }
+
+    #[test]
+    fn test_session_token() {
+        let token = SessionToken::generate();
+        assert!(token != SessionToken::generate());
+        assert_eq!(token.to_string().parse::<SessionToken>(), Ok(token));
+        assert_eq!(format!("{:?}", token), "SessionToken(..)");
+
+        assert!("00ff".parse::<SessionToken>().is_err());
+        assert!("zz00000000000000000000000000000000".parse::<SessionToken>().is_err());
+        assert_eq!("000102030405060708090a0b0c0d0e0f".parse::<SessionToken>(),
+                   Ok(SessionToken([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])));
+    }
 }
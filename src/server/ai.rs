@@ -0,0 +1,61 @@
+//! Control of the units of abandoned players.
+
+use state::{GameState, ClientId, UnitId};
+
+/// Return a target for every unit of the player: the nearest unit or building
+/// of another player that has not been eliminated.
+pub fn targets(game: &GameState, id: ClientId) -> Vec<(UnitId, [f64; 2])> {
+    let player = match game.player(id) {
+        Some(player) => player,
+        None => return vec![],
+    };
+    let enemies: Vec<[f64; 2]> = game.players.iter()
+        .filter(|other| other.id != id && !other.eliminated)
+        .flat_map(|other| {
+            other.units.iter().map(|unit| unit.position)
+                .chain(other.buildings.iter().map(|building| building.position))
+        })
+        .collect();
+
+    let mut targets = vec![];
+    for unit in player.units.iter() {
+        let mut nearest: Option<([f64; 2], f64)> = None;
+        for &position in enemies.iter() {
+            let distance = unit.distance_to(position);
+            if nearest.map_or(true, |(_, d)| distance < d) {
+                nearest = Some((position, distance));
+            }
+        }
+        if let Some((target, _)) = nearest {
+            targets.push((unit.id, target));
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod test {
+    use super::targets;
+    use settings::DEFAULT_UNIT_HEALTH;
+    use state::{GameState, Player, Unit, Faction};
+
+    #[test]
+    fn test_attack_nearest_enemy() {
+        let mut game = GameState::new();
+        let mut ai = Player::new(0, Faction::Rock);
+        ai.units.push(Unit::new(0, [100.0, 100.0], DEFAULT_UNIT_HEALTH));
+        ai.units.push(Unit::new(1, [500.0, 100.0], DEFAULT_UNIT_HEALTH));
+        let mut enemy = Player::new(1, Faction::Paper);
+        enemy.units.push(Unit::new(2, [150.0, 100.0], DEFAULT_UNIT_HEALTH));
+        enemy.units.push(Unit::new(3, [400.0, 100.0], DEFAULT_UNIT_HEALTH));
+        let mut eliminated = Player::new(2, Faction::Scissors);
+        eliminated.eliminated = true;
+        eliminated.units.push(Unit::new(4, [500.0, 110.0], DEFAULT_UNIT_HEALTH));
+        game.players.push(ai);
+        game.players.push(enemy);
+        game.players.push(eliminated);
+
+        assert_eq!(targets(&game, 0.into()), vec![(0.into(), [150.0, 100.0]), (1.into(), [400.0, 100.0])]);
+        assert_eq!(targets(&game, 42.into()), vec![]);
+    }
+}
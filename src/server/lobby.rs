@@ -0,0 +1,175 @@
+//! Phases of a match on the server.
+
+use std::time::{Duration, Instant};
+
+use state::{GameState, ClientId};
+use network::{Message, Phase, LobbyPlayer};
+use super::millis_between;
+
+/// Number of players needed to start a match
+pub const MIN_PLAYERS: usize = 3;
+
+/// Time in ms from all players being ready to the start of the match
+pub const COUNTDOWN_MS: u64 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Open,
+    /// The match starts at the given time
+    Countdown(Instant),
+    Running,
+    Finished,
+}
+
+/// The phase machine of a match: Lobby → Countdown → Running → Finished.
+///
+/// Players join during the lobby and the countdown. The countdown starts once
+/// enough players joined and all of them are ready. It is cancelled when a
+/// player joins, leaves or is not ready anymore.
+#[derive(Debug)]
+pub struct Lobby {
+    state: State,
+    /// Players that are ready to start
+    ready: Vec<ClientId>,
+}
+
+impl Lobby {
+    pub fn new() -> Lobby {
+        Lobby {
+            state: State::Open,
+            ready: vec![],
+        }
+    }
+
+    /// Return the phase at `now`, the countdown in whole seconds rounded up.
+    pub fn phase(&self, now: Instant) -> Phase {
+        match self.state {
+            State::Open => Phase::Lobby,
+            State::Countdown(start) => Phase::Countdown((millis_between(now, start) + 999) / 1000),
+            State::Running => Phase::Running,
+            State::Finished => Phase::Finished,
+        }
+    }
+
+    /// Whether new players may join the match.
+    pub fn is_open(&self) -> bool {
+        match self.state {
+            State::Open | State::Countdown(_) => true,
+            State::Running | State::Finished => false,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    pub fn is_ready(&self, id: ClientId) -> bool {
+        self.ready.contains(&id)
+    }
+
+    /// Set whether the player is ready to start.
+    ///
+    /// Returns false if the match has started already.
+    pub fn set_ready(&mut self, id: ClientId, ready: bool) -> bool {
+        if !self.is_open() {
+            return false;
+        }
+        self.leave(id);
+        if ready {
+            self.ready.push(id);
+        }
+        true
+    }
+
+    /// Forget a player who left the lobby.
+    pub fn leave(&mut self, id: ClientId) {
+        self.ready.retain(|&other| other != id);
+    }
+
+    /// Advance the phase, given the players that joined the match.
+    ///
+    /// Returns true if the match starts.
+    pub fn update(&mut self, players: &[ClientId], now: Instant) -> bool {
+        let ready = players.len() >= MIN_PLAYERS && players.iter().all(|&id| self.is_ready(id));
+        match self.state {
+            State::Open if ready => {
+                self.state = State::Countdown(now + Duration::from_millis(COUNTDOWN_MS));
+                false
+            }
+            State::Countdown(_) if !ready => {
+                self.state = State::Open;
+                false
+            }
+            State::Countdown(start) if now >= start => {
+                self.state = State::Running;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.state = State::Finished;
+    }
+
+    /// Open the lobby for the next match.
+    pub fn reset(&mut self) {
+        *self = Lobby::new();
+    }
+
+    /// Return the message that tells the clients about the phase and the players.
+    pub fn message(&self, game: &GameState, now: Instant) -> Message {
+        let players = game.players.iter()
+            .map(|player| LobbyPlayer { id: player.id, faction: player.faction, ready: self.is_ready(player.id) })
+            .collect();
+        Message::Lobby(self.phase(now), players)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Lobby, COUNTDOWN_MS};
+    use network::Phase;
+    use state::ClientId;
+
+    #[test]
+    fn test_phases() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let players: Vec<ClientId> = vec![0.into(), 1.into(), 2.into()];
+        let mut lobby = Lobby::new();
+
+        // Not enough players
+        lobby.set_ready(0.into(), true);
+        lobby.set_ready(1.into(), true);
+        assert!(!lobby.update(&players[..2], at(0)));
+        assert_eq!(lobby.phase(at(0)), Phase::Lobby);
+
+        assert!(!lobby.update(&players, at(0)));
+        lobby.set_ready(2.into(), true);
+        assert!(!lobby.update(&players, at(100)));
+        assert_eq!(lobby.phase(at(100)), Phase::Countdown(5));
+        assert_eq!(lobby.phase(at(4100)), Phase::Countdown(1));
+
+        // Cancelled and started again
+        lobby.set_ready(1.into(), false);
+        assert!(!lobby.update(&players, at(200)));
+        assert_eq!(lobby.phase(at(200)), Phase::Lobby);
+        lobby.set_ready(1.into(), true);
+        lobby.update(&players, at(300));
+        assert!(!lobby.update(&players, at(300 + COUNTDOWN_MS - 1)));
+        assert!(lobby.update(&players, at(300 + COUNTDOWN_MS)));
+        assert_eq!(lobby.phase(at(300 + COUNTDOWN_MS)), Phase::Running);
+
+        assert!(!lobby.set_ready(0.into(), false));
+        assert!(!lobby.update(&players[..1], at(10000)));
+        assert!(lobby.is_running());
+        lobby.finish();
+        assert!(!lobby.is_open());
+        lobby.reset();
+        assert_eq!(lobby.phase(at(10000)), Phase::Lobby);
+        assert!(!lobby.is_ready(0.into()));
+    }
+}
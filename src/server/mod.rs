@@ -1,269 +1,1643 @@
-use std::io::Write;
 use std::io::Result as IoResult;
-use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::ops::RangeFrom;
-use std::collections::HashMap;
-use std::f64::consts::PI;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
 
-use bincode::{serialize, deserialize_from, Infinite, Bounded};
 
-use state::{WorldState, GameState, Player, Unit, UnitId};
-use network::{Message, Command};
+use state::{GameState, Player, PlayerStatus, Unit, UnitId, ClientId, Faction};
+use settings::GameSettings;
+use codec;
+use network::{Message, Command, CommandError, ErrorCode, PlayerResult, Outcome, SessionToken, ProtocolVersion,
+              Phase, FEATURE_DELTA, TICK_MS, PING_INTERVAL_MS, IDLE_TIMEOUT_MS, millis};
+use delta::{Delta, SNAPSHOT_HISTORY};
+use transport::{self, Transport, Connection, Frame};
+use simulator::{self, Conditions};
+use discovery::{self, ServerInfo, DISCOVERY_PORT};
+
+pub mod ai;
+pub mod lobby;
+
+use self::lobby::Lobby;
+
+/// Time in ms the connections get to deliver the results of a finished match
+const GAME_OVER_DELAY_MS: u64 = 1000;
+
+/// Number of frames queued for a client before snapshots are skipped, which
+/// are 2.5 s of snapshots
+pub const OUTBOUND_FRAMES: usize = 256;
+
+/// Number of players a server accepts unless configured otherwise
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
+
+/// What the server does once a match is over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchEnd {
+    /// Stop serving
+    Shutdown,
+    /// Reset the game and wait for the players of the next match
+    Lobby,
+}
+
+/// Time in ms a disconnected player has to reconnect unless configured otherwise
+pub const DEFAULT_GRACE_PERIOD_MS: u64 = 30_000;
+
+/// What happens to the units of a player who did not reconnect in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbandonedUnits {
+    /// Remove the units and buildings, which eliminates the player
+    Remove,
+    /// Let the server attack the nearest enemies with them
+    Ai,
+    /// Leave them where they are, they still defend themselves
+    Freeze,
+}
+
+impl FromStr for AbandonedUnits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AbandonedUnits, String> {
+        match s {
+            "remove" => Ok(AbandonedUnits::Remove),
+            "ai" => Ok(AbandonedUnits::Ai),
+            "freeze" => Ok(AbandonedUnits::Freeze),
+            _ => Err(format!("Unknown handling of abandoned units: {}, expected remove, ai or freeze", s)),
+        }
+    }
+}
 
 /// A `Server` instance holds global server state.
 pub struct Server {
     socket_addr: SocketAddr,
-    world: Arc<Mutex<WorldState>>,
+    /// Settings of the matches, sent to the clients in the handshake
+    settings: Arc<GameSettings>,
     game: Arc<Mutex<GameState>>,
     /// Generator that returns sequential unit IDs
     unit_id_generator: Arc<Mutex<RangeFrom<u32>>>,
     /// Generator that returns sequential client IDs
     client_id_generator: Arc<Mutex<RangeFrom<u32>>>,
+    /// Generator that returns sequential building IDs
+    building_id_generator: Arc<Mutex<RangeFrom<u32>>>,
 
     /// Map with active unit move commands
     unit_targets: Arc<Mutex<HashMap<UnitId, [f64; 2]>>>,
+    /// The session tokens that allow clients to reconnect
+    sessions: SafeSessions,
+    /// Outbound queues of the connected clients
+    clients: SafeClients,
+
+    /// Phase of the match and the players that are ready
+    lobby: SafeLobby,
+    /// What to do once a match is over
+    match_end: MatchEnd,
+    /// Maximum number of players in a match
+    max_players: usize,
+    /// Time in ms a disconnected player has to reconnect
+    grace_period_ms: u64,
+    /// What happens to the units of players who did not reconnect in time
+    abandoned_units: AbandonedUnits,
+    /// Transport the clients connect over
+    transport: Transport,
+    /// Simulated conditions of the network to the clients
+    conditions: Conditions,
+    /// Name the server announces in the local network, if any
+    name: Option<String>,
+    /// Set when the server should stop serving
+    shutdown: Arc<AtomicBool>,
 }
 
 impl Server {
     pub fn new<T: ToSocketAddrs>(addr: T,
-                                world_size: (f64, f64))
+                                settings: GameSettings)
                                 -> IoResult<Server> {
         let addr = try!(addr.to_socket_addrs()).next().unwrap();
-        let world = Arc::new(Mutex::new(WorldState::new(world_size.0, world_size.1)));
         let game = Arc::new(Mutex::new(GameState::new()));
         Ok(Server {
             socket_addr: addr,
-            world: world,
+            settings: Arc::new(settings),
             game: game,
             client_id_generator: Arc::new(Mutex::new(0..)),
             unit_id_generator: Arc::new(Mutex::new(0..)),
+            building_id_generator: Arc::new(Mutex::new(0..)),
             unit_targets: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            lobby: Arc::new(Mutex::new(Lobby::new())),
+            match_end: MatchEnd::Lobby,
+            max_players: DEFAULT_MAX_PLAYERS,
+            grace_period_ms: DEFAULT_GRACE_PERIOD_MS,
+            abandoned_units: AbandonedUnits::Freeze,
+            transport: Transport::Tcp,
+            conditions: Conditions::default(),
+            name: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Set what the server does once a match is over.
+    pub fn set_match_end(&mut self, match_end: MatchEnd) {
+        self.match_end = match_end;
+    }
+
+    /// Set the transport the clients connect over.
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Simulate the given network conditions on the connections to the clients.
+    pub fn set_network_conditions(&mut self, conditions: Conditions) {
+        self.conditions = conditions;
+    }
+
+    /// Set the time in ms a disconnected player has to reconnect.
+    pub fn set_grace_period(&mut self, grace_period_ms: u64) {
+        self.grace_period_ms = grace_period_ms;
+    }
+
+    /// Set what happens to the units of players who did not reconnect in time.
+    pub fn set_abandoned_units(&mut self, abandoned_units: AbandonedUnits) {
+        self.abandoned_units = abandoned_units;
+    }
+
+    /// Set the maximum number of players in a match.
+    pub fn set_max_players(&mut self, max_players: usize) {
+        self.max_players = max_players;
+    }
+
+    /// Answer discovery probes in the local network under the given name.
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
     pub fn serve(&self) {
-        let tcp_listener = TcpListener::bind(self.socket_addr).unwrap();
-        println!("Start server: {:?}", tcp_listener);
+        let listener = transport::bind(self.transport, self.socket_addr).unwrap();
+        println!("Start server: {:?} on {}", self.transport, self.socket_addr);
+        if let Some(ref name) = self.name {
+            self.announce(name.clone());
+        }
 
         let game_clone = self.game.clone();
         let unit_targets_clone = self.unit_targets.clone();
+        let sessions_clone = self.sessions.clone();
+        let clients_clone = self.clients.clone();
+        let unit_id_generator_clone = self.unit_id_generator.clone();
+        let lobby_clone = self.lobby.clone();
+        let settings_clone = self.settings.clone();
+        let match_end = self.match_end;
+        let grace_period_ms = self.grace_period_ms;
+        let abandoned_units = self.abandoned_units;
+        let shutdown_clone = self.shutdown.clone();
         thread::spawn(move || {
-            update_world(game_clone, unit_targets_clone);
+            update_world(game_clone, unit_id_generator_clone, unit_targets_clone, sessions_clone, clients_clone,
+                         lobby_clone, settings_clone, match_end, grace_period_ms, abandoned_units,
+                         shutdown_clone);
         });
 
-        for stream in tcp_listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let world_clone = self.world.clone();
+        // Poll for new connections, so that the shutdown flag is noticed
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok(Some(connection)) => {
+                    let connection = match simulator::simulate(connection, self.conditions) {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            println!("{:?}", e);
+                            continue;
+                        }
+                    };
+                    let settings_clone = self.settings.clone();
                     let game_clone = self.game.clone();
                     let client_id_generator_clone = self.client_id_generator.clone();
-                    let unit_id_generator_clone = self.unit_id_generator.clone();
+                    let building_id_generator_clone = self.building_id_generator.clone();
                     let unit_targets = self.unit_targets.clone();
+                    let sessions_clone = self.sessions.clone();
+                    let clients_clone = self.clients.clone();
+                    let lobby_clone = self.lobby.clone();
+                    let max_players = self.max_players;
                     println!("Spawning thread...");
                     thread::spawn(move || {
-                        handle_client(stream, world_clone, game_clone,
-                                      client_id_generator_clone, unit_id_generator_clone, unit_targets);
+                        handle_client(connection, settings_clone, game_clone,
+                                      client_id_generator_clone, building_id_generator_clone,
+                                      unit_targets, sessions_clone, clients_clone, lobby_clone, max_players);
                     });
                 }
+                Ok(None) => {
+                    thread::sleep(Duration::from_millis(50));
+                }
                 Err(e) => {
                     println!("{:?}", e);
                 }
             }
         }
+        println!("Shut down server");
+    }
+
+    /// Answer discovery probes on `DISCOVERY_PORT` in the background.
+    ///
+    /// Only one server per host can do so.
+    fn announce(&self, name: String) {
+        let socket = match UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("Could not listen for discovery probes on port {}: {}", DISCOVERY_PORT, e);
+                return;
+            }
+        };
+        println!("Announcing the server as {:?}", name);
+        let game = self.game.clone();
+        let lobby = self.lobby.clone();
+        let port = self.socket_addr.port();
+        let transport = self.transport;
+        let max_players = self.max_players as u32;
+        let shutdown = self.shutdown.clone();
+        thread::spawn(move || {
+            let info = || {
+                let players = game.lock().unwrap().players.len() as u32;
+                ServerInfo {
+                    version: ProtocolVersion::current(),
+                    name: name.clone(),
+                    port: port,
+                    transport: transport,
+                    players: players,
+                    max_players: max_players,
+                    phase: lobby.lock().unwrap().phase(Instant::now()),
+                }
+            };
+            if let Err(e) = discovery::answer(&socket, info, &shutdown) {
+                println!("Stopped answering discovery probes: {:?}", e);
+            }
+        });
     }
 }
 
-pub type SafeWorldState = Arc<Mutex<WorldState>>;
 pub type SafeUnitTargets = Arc<Mutex<HashMap<UnitId, [f64; 2]>>>;
+pub type SafeSessions = Arc<Mutex<HashMap<ClientId, SessionToken>>>;
+pub type SafeLobby = Arc<Mutex<Lobby>>;
+pub type SafeClients = Arc<Mutex<HashMap<ClientId, Client>>>;
+
+/// A connected client as seen by the tick loop.
+pub struct Client {
+    /// Queue of encoded frames that are written to the client by its writer thread
+    pub outbound: SyncSender<Frame>,
+    /// Whether the client understands `DeltaGamestate` messages
+    pub deltas: bool,
+    /// Tick of the last snapshot the client acknowledged
+    pub ack: Option<u64>,
+    /// Time of the handshake, the timestamps of pings are relative to it
+    pub connected: Instant,
+    /// Time the last message of the client was received
+    pub last_seen: Instant,
+    /// Time the last ping was sent to the client
+    pub last_ping: Option<Instant>,
+    /// Last measured round trip time in ms
+    pub rtt: Option<u64>,
+}
 
-pub fn handle_client(mut stream: TcpStream,
-                     world: SafeWorldState,
+impl Client {
+    pub fn new(outbound: SyncSender<Frame>, deltas: bool) -> Client {
+        let now = Instant::now();
+        Client {
+            outbound: outbound,
+            deltas: deltas,
+            ack: None,
+            connected: now,
+            last_seen: now,
+            last_ping: None,
+            rtt: None,
+        }
+    }
+
+    /// Queue the frame for the writer thread.
+    ///
+    /// Unreliable frames are skipped while the queue is full. Returns `false`
+    /// if the client has to be dropped, because its writer thread has
+    /// terminated or it does not keep up with the reliable frames.
+    pub fn send(&self, frame: Frame) -> bool {
+        match self.outbound.try_send(frame) {
+            Ok(()) => true,
+            Err(TrySendError::Full(frame)) => {
+                if frame.reliable {
+                    println!("Client does not keep up with the frames sent to it");
+                }
+                !frame.reliable
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Return the time in ms from `earlier` to `later`, or 0 if `later` is earlier.
+fn millis_between(earlier: Instant, later: Instant) -> u64 {
+    if later > earlier {
+        millis(later - earlier)
+    } else {
+        0
+    }
+}
+
+pub fn handle_client(mut connection: Box<Connection>,
+                     settings: Arc<GameSettings>,
                      game: Arc<Mutex<GameState>>,
                      client_id_generator: Arc<Mutex<RangeFrom<u32>>>,
-                     unit_id_generator: Arc<Mutex<RangeFrom<u32>>>,
-                     unit_targets: SafeUnitTargets) {
+                     building_id_generator: Arc<Mutex<RangeFrom<u32>>>,
+                     unit_targets: SafeUnitTargets,
+                     sessions: SafeSessions,
+                     clients: SafeClients,
+                     lobby: SafeLobby,
+                     max_players: usize) {
 
     // handle client hello
-    let client_message = deserialize_from(&mut stream, Bounded(128));
+    let client_id: ClientId;
+    // Identifies the connection, a reconnect hands out a new token
+    let session: SessionToken;
+    // Connections that never say hello are closed like silent clients
+    if let Err(e) = connection.set_read_timeout(Some(Duration::from_millis(IDLE_TIMEOUT_MS))) {
+        println!("Error: {:?}", e);
+    }
+    let client_message = connection.receive();
     match client_message {
         Ok(message) => {
+            // Refuse clients speaking an incompatible protocol
+            let server_version = ProtocolVersion::current();
+            let protocol = match message {
+                Message::ClientHello(version) | Message::ClientReconnect(version, _, _) => {
+                    if !server_version.is_compatible(&version) {
+                        println!("Client speaks protocol version {}, server {}", version, server_version);
+                        if let Err(e) = connection.send(&Message::VersionMismatch(server_version)) {
+                            println!("Error: {:?}", e);
+                        }
+                        connection.shutdown();
+                        return  // Don't enter game loop
+                    }
+                    server_version.negotiate(&version)
+                }
+                _ => server_version,
+            };
+
             match message {
-                Message::ClientHello => {
+                Message::ClientHello(_) => {
                     // Get exclusive world access
                     let mut game_lock = game.lock().unwrap();
+                    // The match starts while holding the game lock
+                    let phase = lobby.lock().unwrap().phase(Instant::now());
+                    if phase == Phase::Running {
+                        reject(&mut *connection, ErrorCode::GameAlreadyRunning,
+                               "The match has started, only its players may reconnect".into());
+                        return  // Don't enter game loop
+                    }
+                    if phase == Phase::Finished {
+                        reject(&mut *connection, ErrorCode::MatchFinished,
+                               "The match is over, not accepting new players".into());
+                        return  // Don't enter game loop
+                    }
+                    if game_lock.players.len() >= max_players {
+                        reject(&mut *connection, ErrorCode::ServerFull,
+                               format!("The match already has {} players", max_players));
+                        return  // Don't enter game loop
+                    }
 
                     // Create new player for the newly connected client
-                    let client_id = client_id_generator
+                    let id = client_id_generator
                         .lock().expect("Could not lock client_id_generator mutex")
                         .next().expect("No more client IDs available!");
-                    let mut player = Player::new(client_id);
-
-                    // Create four initial units for the player
-                    let coords = [
-                        [50.0f64, 50.0f64], [50.0f64, 100.0f64], [100.0f64, 50.0f64], [100.0f64, 100.0f64],
-                    ];
-                    for coord in coords.iter() {
-                        let unit_id = unit_id_generator
-                            .lock().expect("Could not lock unit_id_generator mutex")
-                            .next().expect("No more unit IDs available!");
-                        player.units.push(Unit::new(unit_id, *coord));
-                    }
+                    let faction = Faction::for_player(game_lock.players.len());
+                    // The units are created once the match starts
+                    let player = Player::new(id, faction);
 
                     // Add player to the world
                     let player_id = player.id;
+                    client_id = player_id;
                     game_lock.players.push(player);
 
+                    // Hand out the secret needed for reconnecting
+                    let token = SessionToken::generate();
+                    sessions.lock().unwrap().insert(player_id, token);
+                    session = token;
+
                     // Send ServerHello message
-                    let encoded: Vec<u8> = serialize(
-                        &Message::ServerHello(player_id, world.lock().unwrap().clone()),
-                        Infinite
-                    ).unwrap();
-                    stream.write(&encoded).unwrap();
+                    connection.send(&Message::ServerHello(protocol, player_id, token, (*settings).clone()))
+                        .unwrap();
+                    // Still holding the game lock, so that the tick loop sees the player connected
+                    register(&*connection, &clients, player_id, protocol.has_feature(FEATURE_DELTA));
+                    let message = lobby.lock().unwrap().message(&game_lock, Instant::now());
+                    queue(&clients, player_id, &message);
                 },
-                Message::ClientReconnect(id) => {
+                Message::ClientReconnect(_, id, token) => {
                     // Get exclusive world access
-                    let world_lock = world.lock().unwrap();
                     let game_lock = game.lock().unwrap();
+                    let mut sessions_lock = sessions.lock().unwrap();
 
-                    // Find player with specified id
-                    match game_lock.players.iter().find(|player| player.id == id) {
-                        Some(_) => {
-                            println!("Found you :)");
-
-                            // Send ServerHello message
-                            let encoded: Vec<u8> = serialize(
-                                &Message::ServerHello(id, world_lock.clone()),
-                                Infinite
-                            ).unwrap();
-                            stream.write(&encoded).unwrap();
-                        },
-                        None => {
-                            println!("Reconnect to id {} not possible", id);
-
-                            // Send Error message
-                            let encoded: Vec<u8> = serialize(
-                                &Message::Error,
-                                Infinite).unwrap();
-                            stream.write(&encoded).unwrap();
-                            return  // Don't enter game loop
-                        }
+                    // The token must be the one handed out last to this client. Tokens of
+                    // previous sessions or matches are not valid anymore.
+                    if game_lock.player(id).is_none() {
+                        reject(&mut *connection, ErrorCode::UnknownClient,
+                               format!("There is no player with ID {}", id));
+                        return  // Don't enter game loop
+                    }
+                    if sessions_lock.get(&id) == Some(&token) {
+                        println!("Found you :)");
+                        client_id = id;
+
+                        // Replace the token, so that it can only be used once
+                        let token = SessionToken::generate();
+                        sessions_lock.insert(id, token);
+                        session = token;
+
+                        // Send ServerHello message
+                        connection.send(&Message::ServerHello(protocol, id, token, (*settings).clone()))
+                            .unwrap();
+                        register(&*connection, &clients, id, protocol.has_feature(FEATURE_DELTA));
+                        let message = lobby.lock().unwrap().message(&game_lock, Instant::now());
+                        queue(&clients, id, &message);
+                    } else {
+                        reject(&mut *connection, ErrorCode::InvalidToken,
+                               format!("The session token for player {} is not valid", id));
+                        return  // Don't enter game loop
                     }
                 },
                 _ => {
-                    println!("Did not receive ClientHello: {:?}", message);
-                    let encoded: Vec<u8> = serialize(&Message::Error, Infinite).unwrap();
-                    stream.write(&encoded).unwrap();
+                    reject(&mut *connection, ErrorCode::UnexpectedMessage,
+                           format!("Expected ClientHello or ClientReconnect, got {:?}", message));
                     return  // Don't enter game loop
                 }
             }
         }
+        Err(codec::Error::Encoding(e)) => {
+            reject(&mut *connection, ErrorCode::ProtocolMismatch, format!("Could not decode message: {}", e));
+            return  // Don't enter game loop
+        }
         Err(e) => {
             println!("Error: {:?}", e);
+            connection.shutdown();
             return  // Don't enter game loop
         }
     }
 
-    let mut command_stream = stream.try_clone().unwrap();
-    let world_clone = world.clone();
-    let game_clone = game.clone();
-    let unit_targets_clone = unit_targets.clone();
-    // Command receiver loop
-    thread::spawn(move || {
-        loop {
-            let client_message = deserialize_from(&mut command_stream, Bounded(128));
-            match client_message {
-                Ok(message) => {
-                    match message {
-                        Message::Command(command) => {
-                            let world_lock = world_clone.lock().unwrap();
-                            let mut game_lock = game_clone.lock().unwrap();
-                            let mut unit_targets_lock = unit_targets_clone.lock().unwrap();
-                            handle_command(&world_lock, &mut game_lock, &mut unit_targets_lock, &command);
-                        },
-                        _ => {
-                            println!("Did receive unexpected message: {:?}", message);
-                            let encoded: Vec<u8> = serialize(&Message::Error, Infinite).unwrap();
-                            command_stream.write(&encoded).unwrap();
-                            return
-                        },
-                    }
-                },
-                Err(e) => {
-                    println!("Error: {:?}", e);
-                    return;
-                }
-            };
-        }
-    });
+    // Connected clients are timed out by the tick loop
+    if let Err(e) = connection.set_read_timeout(None) {
+        println!("Error: {:?}", e);
+    }
 
-    // GameState loop
+    // Command receiver loop
     loop {
-        let encoded: Vec<u8> = {
-            let game_lock = game.lock().unwrap();
-            serialize(&*game_lock, Infinite).unwrap()
-        };
-        match stream.write(&encoded) {
+        let client_message = connection.receive();
+        match client_message {
+            Ok(message) => {
+                if let Some(client) = clients.lock().unwrap().get_mut(&client_id) {
+                    client.last_seen = Instant::now();
+                }
+                match message {
+                    Message::Command(sequence, command) => {
+                        let result = {
+                            let mut game_lock = game.lock().unwrap();
+                            let mut unit_targets_lock = unit_targets.lock().unwrap();
+                            let mut building_id_generator_lock = building_id_generator.lock().unwrap();
+                            let result = handle_command(client_id, &settings, &mut game_lock,
+                                                        &mut unit_targets_lock,
+                                                        &mut building_id_generator_lock, &command);
+                            // Rejected commands count as processed, the client drops their prediction
+                            if let Some(player) = game_lock.player_mut(client_id) {
+                                player.last_command = sequence;
+                            }
+                            result
+                        };
+                        if let Err(e) = result {
+                            println!("Rejected command {:?} of client {}: {}", command, client_id, e);
+                            queue_error(&clients, client_id, e.code(), e.to_string());
+                        }
+                    },
+                    Message::Ack(tick) => {
+                        if let Some(client) = clients.lock().unwrap().get_mut(&client_id) {
+                            if client.ack.map_or(true, |ack| tick > ack) {
+                                client.ack = Some(tick);
+                            }
+                        }
+                    },
+                    Message::Ready(ready) => {
+                        if !lobby.lock().unwrap().set_ready(client_id, ready) {
+                            queue_error(&clients, client_id, ErrorCode::UnexpectedMessage,
+                                        "The match has started already".into());
+                        }
+                    },
+                    Message::Ping(timestamp) => queue(&clients, client_id, &Message::Pong(timestamp)),
+                    Message::Pong(timestamp) => {
+                        if let Some(client) = clients.lock().unwrap().get_mut(&client_id) {
+                            let now = millis_between(client.connected, Instant::now());
+                            client.rtt = Some(now.saturating_sub(timestamp));
+                        }
+                    },
+                    _ => {
+                        queue_error(&clients, client_id, ErrorCode::UnexpectedMessage,
+                                    format!("Expected Command, got {:?}", message));
+                        break;
+                    },
+                }
+            },
+            Err(codec::Error::Encoding(e)) => {
+                queue_error(&clients, client_id, ErrorCode::ProtocolMismatch,
+                            format!("Could not decode message: {}", e));
+                break;
+            }
             Err(e) => {
                 println!("Error: {:?}", e);
-                return;
+                break;
             }
-            _ => thread::sleep(Duration::from_millis(10)),
         };
     }
+
+    // Dropping the queue closes the connection once the queued frames are
+    // written, the tick loop then notices that the player is gone. A
+    // reconnect may have replaced the queue already.
+    let sessions = sessions.lock().unwrap();
+    if sessions.get(&client_id) == Some(&session) {
+        println!("Client {} disconnected", client_id);
+        clients.lock().unwrap().remove(&client_id);
+    }
+}
+
+/// Start writing the frames queued for the client to the connection.
+///
+/// Replaces the queue of a previous connection of a reconnecting client.
+fn register(connection: &Connection, clients: &SafeClients, client_id: ClientId, deltas: bool) {
+    // Writer loop, the connection is closed once the outbound queue is dropped
+    let (outbound, frames) = sync_channel::<Frame>(OUTBOUND_FRAMES);
+    let mut writer = connection.try_clone().unwrap();
+    thread::spawn(move || {
+        for frame in frames.iter() {
+            if let Err(e) = writer.send_frame(&frame) {
+                println!("Error: {:?}", e);
+                return;
+            }
+        }
+        // Also ends the command receiver loop
+        writer.shutdown();
+    });
+    clients.lock().unwrap().insert(client_id, Client::new(outbound, deltas));
+}
+
+/// Report an error to the client and close the connection.
+///
+/// Closing also releases the resources of the transport, e.g. the peer of a
+/// UDP connection.
+fn reject(connection: &mut Connection, code: ErrorCode, description: String) {
+    println!("{}: {}", code, description);
+    if let Err(e) = connection.send(&Message::Error(code, description)) {
+        println!("Error: {:?}", e);
+    }
+    connection.shutdown();
+}
+
+/// Report an error to a connected client through its outbound queue.
+fn queue_error(clients: &SafeClients, client_id: ClientId, code: ErrorCode, description: String) {
+    println!("{}: {}", code, description);
+    queue(clients, client_id, &Message::Error(code, description));
+}
+
+/// Queue the message for the client.
+fn queue(clients: &SafeClients, client_id: ClientId, message: &Message) {
+    let frame = Frame::new(message).unwrap();
+    let mut clients = clients.lock().unwrap();
+    if !clients.get(&client_id).map_or(true, |client| client.send(frame)) {
+        clients.remove(&client_id);
+    }
 }
 
-pub fn handle_command(world: &WorldState,
+/// Ping the clients regularly and forget the ones that went silent.
+///
+/// Dropping the queue of a client closes its connection, also when it is
+/// half-open and writing to it would not fail.
+pub fn keep_alive(clients: &mut HashMap<ClientId, Client>, now: Instant) {
+    clients.retain(|id, client| {
+        if millis_between(client.last_seen, now) > IDLE_TIMEOUT_MS {
+            println!("Client {} timed out", id);
+            return false;
+        }
+        let due = client.last_ping.map_or(true, |ping| millis_between(ping, now) >= PING_INTERVAL_MS);
+        if !due {
+            return true;
+        }
+        client.last_ping = Some(now);
+        let ping = Message::Ping(millis_between(client.connected, now));
+        client.send(Frame::new(&ping).unwrap())
+    });
+}
+
+/// Queue the frame for every connected client.
+///
+/// Clients whose writer thread has terminated or that do not keep up with the
+/// reliable frames are removed, see `Client::send`.
+pub fn broadcast(clients: &mut HashMap<ClientId, Client>, frame: Frame) {
+    clients.retain(|_, client| client.send(frame.clone()));
+}
+
+/// Queue the snapshot of the tick for every connected client.
+///
+/// Clients that support it get a delta against the last snapshot they
+/// acknowledged, or the full snapshot if that is not in the `history` anymore.
+/// Every distinct message is only encoded once.
+pub fn send_snapshot(clients: &mut HashMap<ClientId, Client>,
+                     history: &VecDeque<(u64, GameState)>,
+                     tick: u64,
+                     game: &GameState) {
+    let mut frames: HashMap<Option<u64>, Frame> = HashMap::new();
+    clients.retain(|_, client| {
+        let baseline = if client.deltas {
+            client.ack.and_then(|ack| history.iter().find(|&&(t, _)| t == ack))
+        } else {
+            None
+        };
+        let frame = frames.entry(baseline.map(|&(t, _)| t)).or_insert_with(|| {
+            let message = match baseline {
+                Some(&(t, ref state)) => Message::DeltaGamestate(tick, Delta::between(t, state, game)),
+                None => Message::UpdateGamestate(tick, game.clone()),
+            };
+            Frame::new(&message).unwrap()
+        }).clone();
+        client.send(frame)
+    });
+}
+
+pub fn handle_command(client_id: ClientId,
+                      settings: &GameSettings,
                       game: &mut GameState,
-                      unit_targets: &mut HashMap<UnitId, [f64; 2]>, command: &Command) {
+                      unit_targets: &mut HashMap<UnitId, [f64; 2]>,
+                      building_id_generator: &mut RangeFrom<u32>,
+                      command: &Command) -> Result<(), CommandError> {
     println!("Did receive command {:?}", command);
+    let index = match game.players.iter().position(|player| player.id == client_id) {
+        Some(index) => index,
+        None => return Err(CommandError::UnknownPlayer(client_id)),
+    };
+
+    // Commands may only refer to units and buildings of the sender
+    let unit_error = |game: &GameState, id: UnitId| {
+        if game.unit(id).is_some() {
+            CommandError::ForeignUnit(id)
+        } else {
+            CommandError::UnknownUnit(id)
+        }
+    };
+
     match command {
         &Command::Move(id, move_target) => {
-            for player in game.players.iter_mut() {
-                for unit in player.units.iter_mut() {
-                    if unit.id == id {
-                        println!("Found it :)");
-                        let mut target = [0.0; 2];
-                        target[0] = if move_target[0] > world.x {
-                            world.x
-                        } else if move_target[0] < 0.0 {
-                            0.0
-                        } else {
-                            move_target[0]
-                        };
-                        target[1] = if move_target[1] > world.y {
-                            world.y
-                        } else if move_target[1] < 0.0 {
-                            0.0
-                        } else {
-                            move_target[1]
-                        };
-                        let dx = target[0] - unit.position[0];
-                        let dy = target[1] - unit.position[1];
-                        if dx.is_sign_negative() {
-                            unit.angle = (dy / dx).atan() + PI;
-                        } else {
-                            unit.angle = (dy / dx).atan();
-                        }
-                        unit_targets.insert(id, target);
-                    }
-                }
+            if game.players[index].unit(id).is_none() {
+                return Err(unit_error(game, id));
             }
+            let unit = game.players[index].unit_mut(id).unwrap();
+            let world = &settings.world;
+            let mut target = [0.0; 2];
+            target[0] = if move_target[0] > world.x {
+                world.x
+            } else if move_target[0] < 0.0 {
+                0.0
+            } else {
+                move_target[0]
+            };
+            target[1] = if move_target[1] > world.y {
+                world.y
+            } else if move_target[1] < 0.0 {
+                0.0
+            } else {
+                move_target[1]
+            };
+            unit.face(target);
+            unit_targets.insert(id, target);
             println!("Move {} to {:?}!", id, move_target);
         }
+        &Command::Merge(a, b) => {
+            for &id in [a, b].iter() {
+                if game.players[index].unit(id).is_none() {
+                    return Err(unit_error(game, id));
+                }
+            }
+            if a == b {
+                return Err(CommandError::SameUnit(a));
+            }
+
+            // The units must be next to each other
+            let player = &mut game.players[index];
+            let distance = player.unit(a).unwrap().distance_to(player.unit(b).unwrap().position);
+            if distance > settings.merge_distance() {
+                return Err(CommandError::NotAdjacent(a, b));
+            }
+
+            let building_id = building_id_generator.next().expect("No more building IDs available!");
+            player.merge(a, b, building_id.into());
+            unit_targets.remove(&a);
+            unit_targets.remove(&b);
+            println!("Merged {} and {} into building {}", a, b, building_id);
+        }
+        &Command::Split(id) => {
+            if game.players[index].building(id).is_none() {
+                return Err(if game.building(id).is_some() {
+                    CommandError::ForeignBuilding(id)
+                } else {
+                    CommandError::UnknownBuilding(id)
+                });
+            }
+            game.players[index].split(id, settings.unit_size);
+            println!("Split building {}", id);
+        }
     }
+    Ok(())
 }
 
-pub fn update_world(game: Arc<Mutex<GameState>>, unit_targets: SafeUnitTargets) {
+/// Run the simulation in ticks of `TICK_MS`.
+///
+/// Until the match starts, every tick advances the lobby and tells the clients
+/// about changes of it. Once running, every tick steps the game, encodes a
+/// single snapshot with the tick number and queues it for all connected clients.
+pub fn update_world(game: Arc<Mutex<GameState>>,
+                    unit_id_generator: Arc<Mutex<RangeFrom<u32>>>,
+                    unit_targets: SafeUnitTargets,
+                    sessions: SafeSessions,
+                    clients: SafeClients,
+                    lobby: SafeLobby,
+                    settings: Arc<GameSettings>,
+                    match_end: MatchEnd,
+                    grace_period_ms: u64,
+                    abandoned_units: AbandonedUnits,
+                    shutdown: Arc<AtomicBool>) {
+    // Players that have been eliminated, grouped by the update they were eliminated in
+    let mut eliminations: Vec<Vec<ClientId>> = vec![];
+    // Time the connection of the disconnected players was lost
+    let mut disconnected: HashMap<ClientId, Instant> = HashMap::new();
+    let mut tick: u64 = 0;
+    // Recent snapshots that clients may use as baseline
+    let mut history: VecDeque<(u64, GameState)> = VecDeque::with_capacity(SNAPSHOT_HISTORY);
+    // Last lobby message sent to the clients
+    let mut lobby_message: Option<Message> = None;
+    let mut next_tick = Instant::now();
     loop {
-        {
+        let (snapshot, results, left, message) = {
+            let now = Instant::now();
             let mut game_lock = game.lock().unwrap();
-            let unit_targets = unit_targets.lock().unwrap();
-            game_lock.update_targets(&unit_targets);
-            game_lock.update(5.0);
+            let mut unit_targets = unit_targets.lock().unwrap();
+            // New clients are registered while holding the game lock
+            let connected: Vec<ClientId> = clients.lock().unwrap().keys().cloned().collect();
+            let running = lobby.lock().unwrap().is_running();
+            if running {
+                let left = update_players(&mut game_lock, &mut unit_targets, &connected, &mut disconnected,
+                                          now, grace_period_ms, abandoned_units);
+                game_lock.update_targets(&unit_targets, &settings);
+                game_lock.update(TICK_MS as f64, &settings);
+
+                // Forget the targets of destroyed units
+                unit_targets.retain(|id, _| game_lock.unit(*id).is_some());
+
+                let eliminated = game_lock.eliminate();
+                for id in eliminated.iter() {
+                    println!("Player {} has been eliminated", id);
+                }
+                if !eliminated.is_empty() {
+                    eliminations.push(eliminated);
+                }
+
+                let results = if game_lock.is_finished() {
+                    Some(match_results(&game_lock.remaining(), &eliminations))
+                } else {
+                    None
+                };
+                (Some(game_lock.clone()), results, left, None)
+            } else {
+                let left = leave_lobby(&mut game_lock, &connected);
+                if !left.is_empty() {
+                    let mut sessions = sessions.lock().unwrap();
+                    for id in left.iter() {
+                        sessions.remove(id);
+                    }
+                }
+                let mut lobby = lobby.lock().unwrap();
+                for &id in left.iter() {
+                    lobby.leave(id);
+                }
+                update_lobby(&mut game_lock, &mut lobby, &mut unit_id_generator.lock().unwrap(), &settings, now);
+                (None, None, left, Some(lobby.message(&game_lock, now)))
+            }
+        };
+
+        // Encode outside of the game lock
+        {
+            let mut clients = clients.lock().unwrap();
+            for &id in left.iter() {
+                broadcast(&mut clients, Frame::new(&Message::PlayerLeft(id)).unwrap());
+            }
+            if let Some(ref snapshot) = snapshot {
+                send_snapshot(&mut clients, &history, tick, snapshot);
+            }
+            // New clients got the current state of the lobby during the handshake
+            if message != lobby_message {
+                if let Some(ref message) = message {
+                    broadcast(&mut clients, Frame::new(message).unwrap());
+                }
+            }
+            keep_alive(&mut clients, Instant::now());
+        }
+        lobby_message = message;
+        if let Some(snapshot) = snapshot {
+            if history.len() == SNAPSHOT_HISTORY {
+                history.pop_front();
+            }
+            history.push_back((tick, snapshot));
+        }
+
+        if let Some(results) = results {
+            println!("Game over: {:?}", results);
+            lobby.lock().unwrap().finish();
+            {
+                // Dropping the queues closes the connections once the results are written
+                let mut clients = clients.lock().unwrap();
+                broadcast(&mut clients, Frame::new(&Message::GameOver(results)).unwrap());
+                clients.clear();
+            }
+            thread::sleep(Duration::from_millis(GAME_OVER_DELAY_MS));
+            match match_end {
+                MatchEnd::Shutdown => {
+                    shutdown.store(true, Ordering::SeqCst);
+                    return;
+                }
+                MatchEnd::Lobby => {
+                    *game.lock().unwrap() = GameState::new();
+                    unit_targets.lock().unwrap().clear();
+                    sessions.lock().unwrap().clear();
+                    eliminations.clear();
+                    disconnected.clear();
+                    history.clear();
+                    lobby.lock().unwrap().reset();
+                    println!("Waiting for the players of the next match");
+                }
+            }
+            next_tick = Instant::now();
+        }
+
+        // Keep a fixed rate, skip ticks when falling behind
+        tick += 1;
+        next_tick += Duration::from_millis(TICK_MS);
+        let now = Instant::now();
+        if next_tick > now {
+            thread::sleep(next_tick - now);
+        } else {
+            next_tick = now;
+        }
+    }
+}
+
+/// Update the status of the players from the IDs of the connected clients.
+///
+/// Players without a connection are disconnected. If they do not reconnect
+/// within the grace period, they are abandoned and their units are handled as
+/// configured. Returns the IDs of the players that have been disconnected by
+/// this call.
+pub fn update_players(game: &mut GameState,
+                      unit_targets: &mut HashMap<UnitId, [f64; 2]>,
+                      connected: &[ClientId],
+                      disconnected: &mut HashMap<ClientId, Instant>,
+                      now: Instant,
+                      grace_period_ms: u64,
+                      abandoned_units: AbandonedUnits) -> Vec<ClientId> {
+    let mut left = vec![];
+    for player in game.players.iter_mut() {
+        match (player.status, connected.contains(&player.id)) {
+            (PlayerStatus::Connected, false) => {
+                println!("Player {} disconnected", player.id);
+                player.status = PlayerStatus::Disconnected;
+                disconnected.insert(player.id, now);
+                left.push(player.id);
+            }
+            (PlayerStatus::Disconnected, true) => {
+                println!("Player {} reconnected", player.id);
+                player.status = PlayerStatus::Connected;
+                disconnected.remove(&player.id);
+            }
+            (PlayerStatus::Abandoned, true) => {
+                println!("Player {} reconnected", player.id);
+                player.status = PlayerStatus::Connected;
+                // The units don't follow the targets of the server anymore
+                for unit in player.units.iter() {
+                    unit_targets.remove(&unit.id);
+                }
+            }
+            (PlayerStatus::Disconnected, false) => {
+                let expired = disconnected.get(&player.id)
+                    .map_or(true, |&since| millis_between(since, now) >= grace_period_ms);
+                if expired {
+                    println!("Player {} abandoned the game", player.id);
+                    player.status = PlayerStatus::Abandoned;
+                    disconnected.remove(&player.id);
+                    for unit in player.units.iter() {
+                        unit_targets.remove(&unit.id);
+                    }
+                    if abandoned_units == AbandonedUnits::Remove {
+                        player.units.clear();
+                        player.buildings.clear();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if abandoned_units == AbandonedUnits::Ai {
+        let abandoned: Vec<ClientId> = game.players.iter()
+            .filter(|player| player.status == PlayerStatus::Abandoned)
+            .map(|player| player.id)
+            .collect();
+        for id in abandoned {
+            for (unit, target) in ai::targets(game, id) {
+                unit_targets.insert(unit, target);
+            }
+        }
+    }
+    left
+}
+
+/// Remove the players without a connection from the lobby.
+///
+/// They have nothing to keep before the match starts. The factions of the
+/// remaining players are assigned again, so that they stay distinct. Returns
+/// the IDs of the removed players.
+pub fn leave_lobby(game: &mut GameState, connected: &[ClientId]) -> Vec<ClientId> {
+    let left: Vec<ClientId> = game.players.iter()
+        .map(|player| player.id)
+        .filter(|id| !connected.contains(id))
+        .collect();
+    if left.is_empty() {
+        return left;
+    }
+    for id in left.iter() {
+        println!("Player {} left the lobby", id);
+    }
+    game.players.retain(|player| connected.contains(&player.id));
+    for (i, player) in game.players.iter_mut().enumerate() {
+        player.faction = Faction::for_player(i);
+    }
+    left
+}
+
+/// Advance the lobby with the players of the game and create their initial
+/// units once the countdown is over.
+///
+/// Returns whether the match started.
+pub fn update_lobby(game: &mut GameState,
+                    lobby: &mut Lobby,
+                    unit_id_generator: &mut RangeFrom<u32>,
+                    settings: &GameSettings,
+                    now: Instant) -> bool {
+    let players: Vec<ClientId> = game.players.iter().map(|player| player.id).collect();
+    if !lobby.update(&players, now) {
+        return false;
+    }
+    println!("The match starts with players {:?}", players);
+    spawn_units(game, unit_id_generator, settings);
+    true
+}
+
+/// Create the initial units of every player at the spawn positions.
+pub fn spawn_units(game: &mut GameState, unit_id_generator: &mut RangeFrom<u32>, settings: &GameSettings) {
+    for player in game.players.iter_mut() {
+        for coord in settings.spawn_positions.iter() {
+            let unit_id = unit_id_generator.next().expect("No more unit IDs available!");
+            player.units.push(Unit::new(unit_id, *coord, settings.unit_health));
+        }
+    }
+}
+
+/// Rank the players of a finished match.
+///
+/// The remaining player wins, the others are ranked by the order in which
+/// they have been eliminated. If no player remains, the players eliminated
+/// last share a draw.
+pub fn match_results(remaining: &[ClientId], eliminations: &[Vec<ClientId>]) -> Vec<PlayerResult> {
+    let mut results = vec![];
+    let mut rank = 1;
+    let mut outcome = Outcome::Draw;
+    for &id in remaining.iter() {
+        results.push(PlayerResult { id: id, outcome: Outcome::Victory, rank: rank });
+        outcome = Outcome::Defeat;
+    }
+    rank += remaining.len() as u32;
+    for group in eliminations.iter().rev() {
+        for &id in group.iter() {
+            results.push(PlayerResult { id: id, outcome: outcome, rank: rank });
+        }
+        outcome = Outcome::Defeat;
+        rank += group.len() as u32;
+    }
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::ops::RangeFrom;
+    use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError};
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{match_results, handle_client, handle_command, broadcast, send_snapshot, keep_alive, update_players,
+                leave_lobby, spawn_units, update_lobby, AbandonedUnits, Client, OUTBOUND_FRAMES,
+                SafeUnitTargets, SafeSessions, SafeClients, SafeLobby};
+    use super::lobby::{Lobby, COUNTDOWN_MS};
+    use bincode::deserialize;
+    use codec;
+    use transport::{Connection, Frame, MemoryConnection, pair};
+    use network::{Message, Command, CommandError, ErrorCode, PlayerResult, Outcome, ProtocolVersion,
+                  SessionToken, Phase, millis};
+    use settings::GameSettings;
+    use state::{WorldState, GameState, Player, PlayerStatus, Faction, ClientId, two_players};
+
+    /// The state shared by the connections of a server, without its tick loop.
+    struct Fixture {
+        settings: Arc<GameSettings>,
+        game: Arc<Mutex<GameState>>,
+        client_ids: Arc<Mutex<RangeFrom<u32>>>,
+        unit_ids: Arc<Mutex<RangeFrom<u32>>>,
+        building_ids: Arc<Mutex<RangeFrom<u32>>>,
+        unit_targets: SafeUnitTargets,
+        sessions: SafeSessions,
+        clients: SafeClients,
+        lobby: SafeLobby,
+        max_players: usize,
+    }
+
+    impl Fixture {
+        fn new(max_players: usize) -> Fixture {
+            Fixture {
+                settings: Arc::new(GameSettings::default()),
+                game: Arc::new(Mutex::new(GameState::new())),
+                client_ids: Arc::new(Mutex::new(0..)),
+                unit_ids: Arc::new(Mutex::new(0..)),
+                building_ids: Arc::new(Mutex::new(0..)),
+                unit_targets: Arc::new(Mutex::new(HashMap::new())),
+                sessions: Arc::new(Mutex::new(HashMap::new())),
+                clients: Arc::new(Mutex::new(HashMap::new())),
+                lobby: Arc::new(Mutex::new(Lobby::new())),
+                max_players: max_players,
+            }
+        }
+
+        /// Handle a client over an in-memory connection and return its end.
+        fn connect(&self) -> MemoryConnection {
+            let (client, server) = pair();
+            self.handle(Box::new(server));
+            client
+        }
+
+        /// Handle the client at the other end of the connection in the
+        /// background, the returned channel is closed once the handler exits.
+        fn handle(&self, connection: Box<Connection>) -> Receiver<()> {
+            let settings = self.settings.clone();
+            let game = self.game.clone();
+            let client_ids = self.client_ids.clone();
+            let building_ids = self.building_ids.clone();
+            let unit_targets = self.unit_targets.clone();
+            let sessions = self.sessions.clone();
+            let clients = self.clients.clone();
+            let lobby = self.lobby.clone();
+            let max_players = self.max_players;
+            let (done, exited) = channel();
+            thread::spawn(move || {
+                handle_client(connection, settings, game, client_ids, building_ids,
+                              unit_targets, sessions, clients, lobby, max_players);
+                drop(done);
+            });
+            exited
+        }
+
+        /// Connect a new player and return its ID and session token.
+        ///
+        /// Returns once the player receives the updates.
+        fn join(&self) -> (MemoryConnection, ClientId, SessionToken) {
+            let connection = self.connect();
+            self.join_over(connection)
+        }
+
+        /// Connect a new player over the given connection, see `join`.
+        fn join_over(&self, mut connection: MemoryConnection) -> (MemoryConnection, ClientId, SessionToken) {
+            connection.send(&Message::ClientHello(ProtocolVersion::current())).unwrap();
+            let (id, token) = match connection.receive().unwrap() {
+                Message::ServerHello(protocol, id, token, settings) => {
+                    assert_eq!(protocol, ProtocolVersion::current());
+                    assert_eq!(settings, *self.settings);
+                    (id, token)
+                }
+                other => panic!("Expected ServerHello, got {:?}", other),
+            };
+            match connection.receive().unwrap() {
+                Message::Lobby(Phase::Lobby, ref players) if players.iter().any(|player| player.id == id) => {}
+                other => panic!("Expected Lobby, got {:?}", other),
+            }
+            sync(&mut connection);
+            (connection, id, token)
+        }
+
+        /// Start the match with the players that joined, as the tick loop does.
+        fn start(&self) {
+            let mut game = self.game.lock().unwrap();
+            let mut lobby = self.lobby.lock().unwrap();
+            for player in game.players.iter() {
+                lobby.set_ready(player.id, true);
+            }
+            let mut unit_ids = self.unit_ids.lock().unwrap();
+            let now = Instant::now();
+            assert!(!update_lobby(&mut game, &mut lobby, &mut unit_ids, &self.settings, now));
+            let end = now + Duration::from_millis(COUNTDOWN_MS);
+            assert!(update_lobby(&mut game, &mut lobby, &mut unit_ids, &self.settings, end));
+        }
+    }
+
+    /// Wait until the server processed the previous messages of the client.
+    ///
+    /// Messages are handled in order, so the answer to an invalid command
+    /// arrives after that.
+    fn sync(connection: &mut MemoryConnection) {
+        connection.send(&Message::Command(0, Command::Split(42.into()))).unwrap();
+        match connection.receive().unwrap() {
+            Message::Error(ErrorCode::InvalidCommand, _) => {}
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    /// Wait up to a second for the condition to become true.
+    fn wait_until<F: Fn() -> bool>(what: &str, condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Timed out waiting until {}", what);
+    }
+
+    fn command(game: &mut GameState, client: u32, command: Command) -> Result<(), CommandError> {
+        let settings = GameSettings::default();
+        let mut unit_targets = HashMap::new();
+        let mut building_ids: RangeFrom<u32> = 0..;
+        handle_command(client.into(), &settings, game, &mut unit_targets, &mut building_ids, &command)
+    }
+
+    #[test]
+    fn test_move_own_unit() {
+        let mut game = two_players();
+        assert_eq!(command(&mut game, 0, Command::Move(1.into(), [200.0, 200.0])), Ok(()));
+    }
+
+    #[test]
+    fn test_reject_foreign_unit() {
+        let mut game = two_players();
+        let before = game.clone();
+        assert_eq!(command(&mut game, 0, Command::Move(2.into(), [0.0, 0.0])),
+                   Err(CommandError::ForeignUnit(2.into())));
+        assert_eq!(command(&mut game, 1, Command::Merge(2.into(), 1.into())),
+                   Err(CommandError::ForeignUnit(1.into())));
+        assert_eq!(game, before);
+    }
+
+    #[test]
+    fn test_reject_unknown_unit() {
+        let mut game = two_players();
+        assert_eq!(command(&mut game, 0, Command::Move(42.into(), [0.0, 0.0])),
+                   Err(CommandError::UnknownUnit(42.into())));
+        assert_eq!(command(&mut game, 42, Command::Move(0.into(), [0.0, 0.0])),
+                   Err(CommandError::UnknownPlayer(42.into())));
+    }
+
+    #[test]
+    fn test_merge_and_split() {
+        let mut game = two_players();
+        assert_eq!(command(&mut game, 0, Command::Merge(0.into(), 0.into())),
+                   Err(CommandError::SameUnit(0.into())));
+        game.players[0].units[1].position = [300.0, 300.0];
+        assert_eq!(command(&mut game, 0, Command::Merge(0.into(), 1.into())),
+                   Err(CommandError::NotAdjacent(0.into(), 1.into())));
+
+        assert_eq!(command(&mut game, 1, Command::Merge(2.into(), 3.into())), Ok(()));
+        assert_eq!(command(&mut game, 0, Command::Split(0.into())),
+                   Err(CommandError::ForeignBuilding(0.into())));
+        assert_eq!(command(&mut game, 1, Command::Split(1.into())),
+                   Err(CommandError::UnknownBuilding(1.into())));
+        assert_eq!(command(&mut game, 1, Command::Split(0.into())), Ok(()));
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let mut clients = HashMap::new();
+        let (a, frames_a) = sync_channel(OUTBOUND_FRAMES);
+        let (b, frames_b) = sync_channel(OUTBOUND_FRAMES);
+        clients.insert(0.into(), Client::new(a, true));
+        clients.insert(1.into(), Client::new(b, true));
+        drop(frames_b);
+
+        let frame = Frame::new(&Message::GameOver(vec![])).unwrap();
+        broadcast(&mut clients, frame.clone());
+        assert_eq!(frames_a.try_recv(), Ok(frame));
+        // The client that went away is forgotten
+        assert_eq!(clients.len(), 1);
+        assert!(clients.contains_key(&0.into()));
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let (a, frames_a) = sync_channel(2);
+        let mut clients = HashMap::new();
+        clients.insert(0.into(), Client::new(a, true));
+        let snapshot = Frame::new(&Message::UpdateGamestate(1, GameState::new())).unwrap();
+        for _ in 0..3 {
+            broadcast(&mut clients, snapshot.clone());
+        }
+        // The snapshot that did not fit is skipped
+        assert_eq!(clients.len(), 1);
+        assert!(frames_a.try_recv().is_ok());
+
+        broadcast(&mut clients, Frame::new(&Message::PlayerLeft(1.into())).unwrap());
+        assert_eq!(clients.len(), 1);
+        broadcast(&mut clients, Frame::new(&Message::PlayerLeft(2.into())).unwrap());
+        assert!(clients.is_empty());
+    }
+
+    #[test]
+    fn test_send_snapshot() {
+        let (a, frames_a) = sync_channel(OUTBOUND_FRAMES);
+        let (b, frames_b) = sync_channel(OUTBOUND_FRAMES);
+        let (c, frames_c) = sync_channel(OUTBOUND_FRAMES);
+        let mut clients = HashMap::new();
+        clients.insert(0.into(), Client { ack: Some(1), ..Client::new(a, true) });
+        // The baseline is not known anymore
+        clients.insert(1.into(), Client { ack: Some(0), ..Client::new(b, true) });
+        clients.insert(2.into(), Client { ack: Some(1), ..Client::new(c, false) });
+
+        let baseline = two_players();
+        let mut current = baseline.clone();
+        current.players[0].units[0].position = [110.0, 100.0];
+        let mut history = VecDeque::new();
+        history.push_back((1, baseline.clone()));
+        send_snapshot(&mut clients, &history, 2, &current);
+
+        let decode = |frame: Frame| {
+            assert!(!frame.reliable);
+            codec::read_message(&mut &frame.data[..]).unwrap()
+        };
+        match decode(frames_a.recv().unwrap()) {
+            Message::DeltaGamestate(2, delta) => assert_eq!(delta.apply(&baseline), current),
+            other => panic!("Expected delta, got {:?}", other),
+        }
+        let full = Message::UpdateGamestate(2, current.clone());
+        assert_eq!(decode(frames_b.recv().unwrap()), full);
+        assert_eq!(decode(frames_c.recv().unwrap()), full);
+    }
+
+    #[test]
+    fn test_handshake_and_commands() {
+        let server = Fixture::new(8);
+        let (mut connection, id, _) = server.join();
+        server.join();
+        server.join();
+        server.start();
+        let unit = {
+            let game = server.game.lock().unwrap();
+            let player = game.player(id).unwrap();
+            assert_eq!(player.units.len(), 4);
+            player.units[0].id
+        };
+
+        connection.send(&Message::Command(1, Command::Move(unit, [300.0, 300.0]))).unwrap();
+        connection.send(&Message::Command(2, Command::Move(42.into(), [300.0, 300.0]))).unwrap();
+        match connection.receive().unwrap() {
+            Message::Error(ErrorCode::InvalidCommand, _) => {}
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(server.unit_targets.lock().unwrap().get(&unit), Some(&[300.0, 300.0]));
+        // The rejected command counts as processed
+        assert_eq!(server.game.lock().unwrap().player(id).unwrap().last_command, 2);
+    }
+
+    #[test]
+    fn test_reject_foreign_command() {
+        let server = Fixture::new(8);
+        let (mut connection, _, _) = server.join();
+        let (_other, other_id, _) = server.join();
+        server.join();
+        server.start();
+        let unit = server.game.lock().unwrap().player(other_id).unwrap().units[0].id;
+
+        // The client learns why the command was rejected
+        connection.send(&Message::Command(1, Command::Move(unit, [300.0, 300.0]))).unwrap();
+        match connection.receive().unwrap() {
+            Message::Error(ErrorCode::UnauthorizedUnit, _) => {}
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert_eq!(server.unit_targets.lock().unwrap().get(&unit), None);
+    }
+
+    #[test]
+    fn test_updates() {
+        let server = Fixture::new(8);
+        let (mut connection, _, _) = server.join();
+        spawn_units(&mut server.game.lock().unwrap(), &mut server.unit_ids.lock().unwrap(), &server.settings);
+        let baseline = server.game.lock().unwrap().clone();
+        let mut history = VecDeque::new();
+        send_snapshot(&mut server.clients.lock().unwrap(), &history, 1, &baseline);
+        assert_eq!(connection.receive().unwrap(), Message::UpdateGamestate(1, baseline.clone()));
+
+        // Once acknowledged, the snapshot is used as baseline
+        connection.send(&Message::Ack(1)).unwrap();
+        sync(&mut connection);
+        history.push_back((1, baseline.clone()));
+        let mut current = baseline.clone();
+        current.players[0].units[0].position = [60.0, 50.0];
+        send_snapshot(&mut server.clients.lock().unwrap(), &history, 2, &current);
+        match connection.receive().unwrap() {
+            Message::DeltaGamestate(2, delta) => assert_eq!(delta.apply(&baseline), current),
+            other => panic!("Expected delta, got {:?}", other),
         }
-        thread::sleep(Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let server = Fixture::new(1);
+        let (client, server_end) = pair();
+        let handler = server.handle(Box::new(server_end));
+        let (connection, id, token) = server.join_over(client);
+        let version = ProtocolVersion::current();
+
+        let mut other = server.connect();
+        other.send(&Message::ClientHello(version)).unwrap();
+        match other.receive().unwrap() {
+            Message::Error(ErrorCode::ServerFull, _) => {}
+            message => panic!("Expected Error, got {:?}", message),
+        }
+        // The connection is closed
+        assert!(other.receive().is_err());
+
+        let mut other = server.connect();
+        other.send(&Message::ClientReconnect(version, id, SessionToken::generate())).unwrap();
+        match other.receive().unwrap() {
+            Message::Error(ErrorCode::InvalidToken, _) => {}
+            message => panic!("Expected Error, got {:?}", message),
+        }
+        assert!(other.receive().is_err());
+
+        // The new connection replaces the old one
+        let mut other = server.connect();
+        other.send(&Message::ClientReconnect(version, id, token)).unwrap();
+        match other.receive().unwrap() {
+            Message::ServerHello(_, new_id, new_token, _) => {
+                assert_eq!(new_id, id);
+                assert!(new_token != token);
+            }
+            message => panic!("Expected ServerHello, got {:?}", message),
+        }
+        match other.receive().unwrap() {
+            Message::Lobby(Phase::Lobby, _) => {}
+            message => panic!("Expected Lobby, got {:?}", message),
+        }
+        // The handler of the replaced connection exits
+        drop(connection);
+        assert_eq!(handler.recv_timeout(Duration::from_secs(1)), Err(RecvTimeoutError::Disconnected));
+        sync(&mut other);
+    }
+
+    #[test]
+    fn test_first_protocol_version() {
+        let server = Fixture::new(8);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        server.handle(Box::new(listener.accept().unwrap().0));
+
+        // The unframed ClientHello of a peer speaking protocol version 1
+        client.write_all(&[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        let mut answer = vec![];
+        client.read_to_end(&mut answer).unwrap();
+        assert_eq!(deserialize::<Message>(&answer).unwrap(), Message::VersionMismatch(ProtocolVersion::current()));
+    }
+
+    #[test]
+    fn test_ready() {
+        let server = Fixture::new(8);
+        let (mut connection, id, _) = server.join();
+        connection.send(&Message::Ready(true)).unwrap();
+        sync(&mut connection);
+        assert!(server.lobby.lock().unwrap().is_ready(id));
+        connection.send(&Message::Ready(false)).unwrap();
+        sync(&mut connection);
+        assert!(!server.lobby.lock().unwrap().is_ready(id));
+
+        server.join();
+        server.join();
+        server.start();
+        // Does not end the connection
+        connection.send(&Message::Ready(false)).unwrap();
+        match connection.receive().unwrap() {
+            Message::Error(ErrorCode::UnexpectedMessage, _) => {}
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        sync(&mut connection);
+    }
+
+    #[test]
+    fn test_game_already_running() {
+        let server = Fixture::new(8);
+        let (_a, _, _) = server.join();
+        let (_b, _, _) = server.join();
+        let (_c, id, token) = server.join();
+        server.start();
+
+        let version = ProtocolVersion::current();
+        let mut other = server.connect();
+        other.send(&Message::ClientHello(version)).unwrap();
+        match other.receive().unwrap() {
+            Message::Error(ErrorCode::GameAlreadyRunning, _) => {}
+            message => panic!("Expected Error, got {:?}", message),
+        }
+
+        // Players of the match may still reconnect
+        let mut other = server.connect();
+        other.send(&Message::ClientReconnect(version, id, token)).unwrap();
+        match other.receive().unwrap() {
+            Message::ServerHello(_, new_id, _, _) => assert_eq!(new_id, id),
+            message => panic!("Expected ServerHello, got {:?}", message),
+        }
+        match other.receive().unwrap() {
+            Message::Lobby(Phase::Running, ref players) => assert_eq!(players.len(), 3),
+            message => panic!("Expected Lobby, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_leave_lobby() {
+        let mut game = two_players();
+        assert_eq!(leave_lobby(&mut game, &[0.into(), 1.into()]), vec![]);
+        assert_eq!(leave_lobby(&mut game, &[1.into()]), vec![0.into()]);
+        assert_eq!(game.players.len(), 1);
+        assert_eq!(game.players[0].faction, Faction::for_player(0));
+    }
+
+    #[test]
+    fn test_spawn_units() {
+        let settings = GameSettings {
+            world: WorldState::new(400.0, 300.0),
+            unit_health: 500,
+            spawn_positions: vec![[10.0, 20.0], [30.0, 40.0]],
+            ..GameSettings::default()
+        };
+        let mut game = GameState::new();
+        game.players.push(Player::new(0, Faction::for_player(0)));
+        game.players.push(Player::new(1, Faction::for_player(1)));
+        let mut unit_ids: RangeFrom<u32> = 0..;
+        spawn_units(&mut game, &mut unit_ids, &settings);
+        for player in game.players.iter() {
+            let positions: Vec<[f64; 2]> = player.units.iter().map(|unit| unit.position).collect();
+            assert_eq!(positions, settings.spawn_positions);
+            assert!(player.units.iter().all(|unit| unit.health == 500));
+        }
+        assert_eq!(unit_ids.next(), Some(4));
+
+        // Move targets are clamped to the configured world
+        let mut unit_targets = HashMap::new();
+        assert_eq!(handle_command(0.into(), &settings, &mut game, &mut unit_targets, &mut unit_ids,
+                                  &Command::Move(0.into(), [1000.0, 1000.0])), Ok(()));
+        assert_eq!(unit_targets.get(&0.into()), Some(&[400.0, 300.0]));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let (a, frames_a) = sync_channel(OUTBOUND_FRAMES);
+        let (b, frames_b) = sync_channel(OUTBOUND_FRAMES);
+        let mut clients = HashMap::new();
+        clients.insert(0.into(), Client::new(a, true));
+        clients.insert(1.into(), Client::new(b, true));
+        let start = clients[&0.into()].connected;
+
+        keep_alive(&mut clients, start + Duration::from_millis(20));
+        let decode = |frame: Frame| codec::read_message(&mut &frame.data[..]).unwrap();
+        assert_eq!(decode(frames_a.try_recv().unwrap()), Message::Ping(20));
+        // Not again within the interval
+        keep_alive(&mut clients, start + Duration::from_millis(500));
+        assert!(frames_a.try_recv().is_err());
+
+        clients.get_mut(&0.into()).unwrap().last_seen = start + Duration::from_millis(4000);
+        keep_alive(&mut clients, start + Duration::from_millis(6000));
+        assert_eq!(decode(frames_a.try_recv().unwrap()), Message::Ping(6000));
+        // The silent client is disconnected
+        assert_eq!(clients.len(), 1);
+        assert!(frames_b.try_recv().is_ok());
+        assert!(frames_b.recv().is_err());
+    }
+
+    #[test]
+    fn test_ping() {
+        let server = Fixture::new(8);
+        let (mut connection, id, _) = server.join();
+        connection.send(&Message::Ping(42)).unwrap();
+        assert_eq!(connection.receive().unwrap(), Message::Pong(42));
+
+        let before = Instant::now();
+        connection.send(&Message::Pong(0)).unwrap();
+        sync(&mut connection);
+        let clients = server.clients.lock().unwrap();
+        let client = &clients[&id];
+        assert!(client.rtt.unwrap() >= millis(before - client.connected));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let server = Fixture::new(8);
+        let (connection, id, _) = server.join();
+        assert!(server.clients.lock().unwrap().contains_key(&id));
+        connection.shutdown();
+        wait_until("the client has been removed", || server.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_player_lifecycle() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut game = two_players();
+        let mut unit_targets = HashMap::new();
+        unit_targets.insert(0.into(), [300.0, 300.0]);
+        let mut disconnected = HashMap::new();
+        let mut update = |game: &mut GameState, connected: &[ClientId], now| {
+            update_players(game, &mut unit_targets, connected, &mut disconnected, now, 1000,
+                           AbandonedUnits::Freeze)
+        };
+
+        assert_eq!(update(&mut game, &[0.into(), 1.into()], at(0)), vec![]);
+        assert_eq!(update(&mut game, &[1.into()], at(10)), vec![0.into()]);
+        assert_eq!(game.players[0].status, PlayerStatus::Disconnected);
+        // Only reported once
+        assert_eq!(update(&mut game, &[1.into()], at(500)), vec![]);
+        assert_eq!(update(&mut game, &[0.into(), 1.into()], at(600)), vec![]);
+        assert_eq!(game.players[0].status, PlayerStatus::Connected);
+
+        update(&mut game, &[1.into()], at(1000));
+        update(&mut game, &[1.into()], at(1999));
+        assert_eq!(game.players[0].status, PlayerStatus::Disconnected);
+        update(&mut game, &[1.into()], at(2000));
+        assert_eq!(game.players[0].status, PlayerStatus::Abandoned);
+        assert_eq!(game.players[0].units.len(), 2);
+    }
+
+    #[test]
+    fn test_abandoned_units() {
+        let start = Instant::now();
+        let abandon = |game: &mut GameState, unit_targets: &mut HashMap<_, _>, abandoned_units| {
+            let mut disconnected = HashMap::new();
+            update_players(game, unit_targets, &[1.into()], &mut disconnected, start, 0, abandoned_units);
+            update_players(game, unit_targets, &[1.into()], &mut disconnected, start, 0, abandoned_units);
+            assert_eq!(game.players[0].status, PlayerStatus::Abandoned);
+        };
+
+        let mut frozen = two_players();
+        let mut unit_targets = HashMap::new();
+        unit_targets.insert(0.into(), [300.0, 300.0]);
+        unit_targets.insert(2.into(), [300.0, 300.0]);
+        abandon(&mut frozen, &mut unit_targets, AbandonedUnits::Freeze);
+        assert!(!unit_targets.contains_key(&0.into()));
+        assert!(unit_targets.contains_key(&2.into()));
+
+        let mut removed = two_players();
+        abandon(&mut removed, &mut HashMap::new(), AbandonedUnits::Remove);
+        assert!(removed.players[0].is_defeated());
+
+        let mut ai = two_players();
+        let mut unit_targets = HashMap::new();
+        abandon(&mut ai, &mut unit_targets, AbandonedUnits::Ai);
+        assert_eq!(unit_targets.get(&0.into()), Some(&[100.0, 300.0]));
+
+        // The player gets back control over the units
+        update_players(&mut ai, &mut unit_targets, &[0.into(), 1.into()], &mut HashMap::new(), start, 0,
+                       AbandonedUnits::Ai);
+        assert_eq!(ai.players[0].status, PlayerStatus::Connected);
+        assert!(!unit_targets.contains_key(&0.into()));
+    }
+
+    #[test]
+    fn test_match_results() {
+        let results = match_results(&[2.into()], &[vec![0.into()], vec![1.into(), 3.into()]]);
+        assert_eq!(results, vec![
+            PlayerResult { id: 2.into(), outcome: Outcome::Victory, rank: 1 },
+            PlayerResult { id: 1.into(), outcome: Outcome::Defeat, rank: 2 },
+            PlayerResult { id: 3.into(), outcome: Outcome::Defeat, rank: 2 },
+            PlayerResult { id: 0.into(), outcome: Outcome::Defeat, rank: 4 },
+        ]);
+    }
+
+    #[test]
+    fn test_match_results_draw() {
+        let results = match_results(&[], &[vec![0.into()], vec![1.into(), 2.into()]]);
+        assert_eq!(results, vec![
+            PlayerResult { id: 1.into(), outcome: Outcome::Draw, rank: 1 },
+            PlayerResult { id: 2.into(), outcome: Outcome::Draw, rank: 1 },
+            PlayerResult { id: 0.into(), outcome: Outcome::Defeat, rank: 3 },
+        ]);
     }
 }
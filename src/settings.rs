@@ -0,0 +1,148 @@
+//! Settings of a match.
+//!
+//! The server loads them from a TOML file and sends them to the clients in
+//! the `ServerHello`. Settings missing in the file keep their default.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use toml;
+
+use network::TICK_MS;
+use state::WorldState;
+
+/// Health of a unit unless configured otherwise
+pub const DEFAULT_UNIT_HEALTH: u64 = 100_0000;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct GameSettings {
+    /// Width and height of the world in m
+    pub world: WorldState,
+    /// Edge length of the square around a unit in m
+    pub unit_size: f64,
+    /// Fraction of the distance to its target a unit moves per ms
+    pub unit_speed: f64,
+    /// Health of the initial units
+    pub unit_health: u64,
+    /// Positions of the initial units of every player in m
+    pub spawn_positions: Vec<[f64; 2]>,
+}
+
+impl Default for GameSettings {
+    fn default() -> GameSettings {
+        GameSettings {
+            world: WorldState::default(),
+            unit_size: 50.0,
+            unit_speed: 0.0001,
+            unit_health: DEFAULT_UNIT_HEALTH,
+            spawn_positions: vec![[50.0, 50.0], [50.0, 100.0], [100.0, 50.0], [100.0, 100.0]],
+        }
+    }
+}
+
+impl GameSettings {
+    /// Read the settings from a TOML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<GameSettings, Box<Error>> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        let settings: GameSettings = toml::from_str(&content)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Check that a match can be played with the settings.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.world.x > 0.0 && self.world.y > 0.0) {
+            return Err(format!("The world must not be empty, it is {} by {} m", self.world.x, self.world.y));
+        }
+        if !(self.unit_size > 0.0) {
+            return Err(format!("The unit size must be positive, it is {} m", self.unit_size));
+        }
+        if !(self.unit_speed >= 0.0) {
+            return Err(format!("The unit speed must not be negative, it is {}", self.unit_speed));
+        }
+        // Faster units would overshoot their target within a tick
+        if self.unit_speed * TICK_MS as f64 > 1.0 {
+            return Err(format!("The unit speed must be at most {} at a tick of {} ms, it is {}",
+                               1.0 / TICK_MS as f64, TICK_MS, self.unit_speed));
+        }
+        if self.unit_health == 0 {
+            return Err("The units must have health".into());
+        }
+        if self.spawn_positions.is_empty() {
+            return Err("The players need at least one initial unit".into());
+        }
+        for position in self.spawn_positions.iter() {
+            let inside = 0.0 <= position[0] && position[0] <= self.world.x &&
+                0.0 <= position[1] && position[1] <= self.world.y;
+            if !inside {
+                return Err(format!("The spawn position {:?} is outside of the world", position));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the maximum distance between the centers of two units that may
+    /// be merged in m.
+    pub fn merge_distance(&self) -> f64 {
+        self.unit_size * 1.5
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use toml;
+
+    use super::GameSettings;
+    use state::WorldState;
+
+    #[test]
+    fn test_parse() {
+        let settings: GameSettings = toml::from_str("
+            unit_speed = 0.001
+            spawn_positions = [[10.0, 20.0]]
+
+            [world]
+            x = 400.0
+            y = 300.0
+        ").unwrap();
+        assert_eq!(settings, GameSettings {
+            world: WorldState::new(400.0, 300.0),
+            unit_speed: 0.001,
+            spawn_positions: vec![[10.0, 20.0]],
+            ..GameSettings::default()
+        });
+        assert_eq!(settings.validate(), Ok(()));
+
+        let settings: GameSettings = toml::from_str("
+            [world]
+            x = 400.0
+        ").unwrap();
+        assert_eq!(settings.world, WorldState::new(400.0, 600.0));
+    }
+
+    #[test]
+    fn test_example() {
+        let settings: GameSettings = toml::from_str(include_str!("../docs/settings.toml")).unwrap();
+        assert_eq!(settings, GameSettings::default());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(GameSettings::default().validate(), Ok(()));
+        let invalid = vec![
+            GameSettings { world: WorldState::new(0.0, 600.0), ..GameSettings::default() },
+            GameSettings { unit_size: -1.0, ..GameSettings::default() },
+            GameSettings { unit_speed: 0.2, ..GameSettings::default() },
+            GameSettings { unit_health: 0, ..GameSettings::default() },
+            GameSettings { spawn_positions: vec![], ..GameSettings::default() },
+            GameSettings { spawn_positions: vec![[900.0, 50.0]], ..GameSettings::default() },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{:?} is valid", settings);
+        }
+    }
+}
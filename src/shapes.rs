@@ -4,14 +4,32 @@ use self::graphics::types::Triangle;
 use self::graphics::math;
 use super::state;
 
+/// A polygon with four corners.
+pub type Square = [[f64; 2]; 4];
+
 
 pub trait Shape {
-    fn get_shape(&self, size: f64) -> Triangle;
+    /// The polygon describing the outline of the shape.
+    type Outline;
+
+    fn get_shape(&self, size: f64) -> Self::Outline;
 
     fn is_hit(&self, size: f64, position: [f64;2]) -> bool;
 }
 
+/// Center the points around the zero point, rotate them by `angle` and move them to `position`.
+fn place(points: &mut [[f64; 2]], size: f64, angle: f64, position: [f64; 2]) {
+    let rotation_matrix = math::rotate_radians(angle);
+    for point in points.iter_mut() {
+        *point = math::add(*point, [-size / 2.0, -size / 2.0]);
+        *point = math::transform_vec(rotation_matrix, *point);
+        *point = math::add(*point, position);
+    }
+}
+
 impl Shape for state::Unit {
+    type Outline = Triangle;
+
     /// Return the base shape of the unit.
     fn get_shape(&self, size: f64) -> Triangle {
         // Base shape
@@ -22,17 +40,7 @@ impl Shape for state::Unit {
         ];
 
         // Transformations
-        for point in triangle.iter_mut() {
-            // Translate center to zero point
-            *point = math::add(*point, [-size / 2.0, -size / 2.0]);
-
-            // Rotate
-            let rotation_matrix = math::rotate_radians(self.angle);
-            *point = math::transform_vec(rotation_matrix, *point);
-
-            // Translate to effective position
-            *point = math::add(*point, self.position);
-        }
+        place(&mut triangle, size, self.angle, self.position);
 
         triangle
     }
@@ -44,11 +52,36 @@ impl Shape for state::Unit {
     }
 }
 
+impl Shape for state::Building {
+    type Outline = Square;
+
+    /// Return the base shape of the building.
+    fn get_shape(&self, size: f64) -> Square {
+        let mut square: Square = [
+            [0.0, 0.0],   // Bottom left
+            [size, 0.0],  // Bottom right
+            [size, size], // Top right
+            [0.0, size],  // Top left
+        ];
+        place(&mut square, size, self.angle, self.position);
+        square
+    }
+
+    /// Calculate whether or not this building is hit by the point at the specified position.
+    fn is_hit(&self, size: f64, position: [f64;2]) -> bool {
+        // Transform the point into the coordinate system of the building
+        let relative = math::sub(position, self.position);
+        let relative = math::transform_vec(math::rotate_radians(-self.angle), relative);
+        relative[0].abs() <= size / 2.0 && relative[1].abs() <= size / 2.0
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::f64::consts::FRAC_PI_2;
+    use std::f64::consts::{FRAC_PI_2, FRAC_PI_4};
     use super::Shape;
     use super::state;
+    use settings::DEFAULT_UNIT_HEALTH;
 
     #[test]
     fn test_hitbox() {
@@ -57,7 +90,7 @@ mod test {
         //    /\
         //   /__\
         //
-        let mut unit = state::Unit::new(0, [100.0, 100.0]);
+        let mut unit = state::Unit::new(0, [100.0, 100.0], DEFAULT_UNIT_HEALTH);
         unit.angle = FRAC_PI_2;
 
         // The following points should be outside of the hitbox.
@@ -84,4 +117,21 @@ mod test {
         assert_eq!(unit.is_hit(50.0, [100.0 - vertical_distance, 100.0]), false);
         assert_eq!(unit.is_hit(50.0, [100.0 + vertical_distance, 100.0]), false);
     }
+
+    #[test]
+    fn test_building_hitbox() {
+        let units = vec![state::Unit::new(0, [75.0, 100.0], DEFAULT_UNIT_HEALTH),
+                         state::Unit::new(1, [125.0, 100.0], DEFAULT_UNIT_HEALTH)];
+        let mut building = state::Building::new(0, units);
+        assert_eq!(building.position, [100.0, 100.0]);
+
+        assert_eq!(building.is_hit(50.0, [100.0, 100.0]), true);
+        assert_eq!(building.is_hit(50.0, [124.0, 124.0]), true);
+        assert_eq!(building.is_hit(50.0, [126.0, 100.0]), false);
+
+        // Rotated by 45° the corners move onto the axes
+        building.angle = FRAC_PI_4;
+        assert_eq!(building.is_hit(50.0, [124.0, 124.0]), false);
+        assert_eq!(building.is_hit(50.0, [134.0, 100.0]), true);
+    }
 }
@@ -0,0 +1,295 @@
+//! Simulation of bad network conditions.
+//!
+//! A `SimulatedConnection` wraps another connection and delays, drops,
+//! duplicates or reorders the frames it sends. Only frames that may get lost
+//! are dropped, duplicated or reordered: losing a reliable frame delays it and
+//! every reliable frame after it, like a retransmission over TCP would. The
+//! server and the clients each wrap their own connections, so that both
+//! directions are affected.
+
+use std::io;
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::VecDeque;
+
+use rand::{self, Rng};
+
+use codec;
+use network::Message;
+use transport::{Connection, Frame};
+
+/// Minimal time in ms until a lost reliable frame is sent again
+const RETRANSMIT_MS: u64 = 200;
+
+/// Time in ms a reordered frame is held back
+const REORDER_MS: u64 = 50;
+
+/// The conditions of a simulated network.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conditions {
+    /// Time in ms it takes a frame to arrive
+    pub latency_ms: u64,
+    /// Maximal random variation of the latency in ms
+    pub jitter_ms: u64,
+    /// Probability that a frame gets lost
+    pub loss: f64,
+    /// Probability that a frame arrives twice
+    pub duplication: f64,
+    /// Probability that a frame is overtaken by later ones
+    pub reordering: f64,
+}
+
+impl Conditions {
+    /// Return whether the conditions affect any frames.
+    pub fn is_perfect(&self) -> bool {
+        *self == Conditions::default()
+    }
+}
+
+/// Decides when the frames sent arrive at the peer.
+struct Scheduler<R> {
+    conditions: Conditions,
+    rng: R,
+    /// Arrival of the last reliable frame
+    last_reliable: Option<Instant>,
+}
+
+impl<R: Rng> Scheduler<R> {
+    fn new(conditions: Conditions, rng: R) -> Scheduler<R> {
+        Scheduler {
+            conditions: conditions,
+            rng: rng,
+            last_reliable: None,
+        }
+    }
+
+    /// Return the times at which a frame sent at `now` arrives, none if it is lost.
+    fn arrivals(&mut self, now: Instant, reliable: bool) -> Vec<Instant> {
+        let conditions = self.conditions;
+        let mut delay = conditions.latency_ms + self.jitter();
+        if reliable {
+            if self.rng.gen::<f64>() < conditions.loss {
+                delay += 2 * conditions.latency_ms + RETRANSMIT_MS;
+            }
+            let mut arrival = now + Duration::from_millis(delay);
+            if let Some(last) = self.last_reliable {
+                if last > arrival {
+                    arrival = last;
+                }
+            }
+            self.last_reliable = Some(arrival);
+            return vec![arrival];
+        }
+
+        if self.rng.gen::<f64>() < conditions.loss {
+            return vec![];
+        }
+        if self.rng.gen::<f64>() < conditions.reordering {
+            delay += conditions.jitter_ms + REORDER_MS;
+        }
+        let mut arrivals = vec![now + Duration::from_millis(delay)];
+        if self.rng.gen::<f64>() < conditions.duplication {
+            let duplicate = delay + self.jitter();
+            arrivals.push(now + Duration::from_millis(duplicate));
+        }
+        arrivals
+    }
+
+    fn jitter(&mut self) -> u64 {
+        self.rng.gen_range(0, self.conditions.jitter_ms + 1)
+    }
+}
+
+enum Event {
+    Frame(Instant, Frame),
+    Shutdown,
+}
+
+/// A connection that sends its frames over a simulated network.
+pub struct SimulatedConnection {
+    inner: Box<Connection>,
+    /// Queue of the thread that sends the frames once they are due
+    events: Sender<Event>,
+}
+
+impl SimulatedConnection {
+    pub fn new(inner: Box<Connection>, conditions: Conditions) -> io::Result<SimulatedConnection> {
+        let mut writer = inner.try_clone()?;
+        let (events, receiver) = channel();
+        thread::spawn(move || {
+            let mut scheduler = Scheduler::new(conditions, rand::thread_rng());
+            // Frames in order of their arrival, `None` closes the connection
+            let mut pending: VecDeque<(Instant, Option<Frame>)> = VecDeque::new();
+            let mut open = true;
+            loop {
+                let now = Instant::now();
+                let event = match pending.front() {
+                    Some(&(due, _)) if !open => {
+                        if due > now {
+                            thread::sleep(due - now);
+                        }
+                        Err(RecvTimeoutError::Timeout)
+                    }
+                    Some(&(due, _)) => {
+                        receiver.recv_timeout(if due > now { due - now } else { Duration::from_millis(0) })
+                    }
+                    None if !open => return,
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match event {
+                    Ok(Event::Frame(sent, frame)) => {
+                        for arrival in scheduler.arrivals(sent, frame.reliable) {
+                            insert(&mut pending, arrival, Some(frame.clone()));
+                        }
+                    }
+                    Ok(Event::Shutdown) => {
+                        // After every frame sent before
+                        let arrival = pending.back().map_or(now, |&(due, _)| due);
+                        pending.push_back((arrival, None));
+                        open = false;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => open = false,
+                }
+
+                while pending.front().map_or(false, |&(due, _)| due <= Instant::now()) {
+                    match pending.pop_front().unwrap().1 {
+                        Some(frame) => {
+                            if let Err(e) = writer.send_frame(&frame) {
+                                println!("Error: {:?}", e);
+                                return;
+                            }
+                        }
+                        None => {
+                            writer.shutdown();
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(SimulatedConnection {
+            inner: inner,
+            events: events,
+        })
+    }
+}
+
+/// Send the frames of the connection over a network with the given conditions.
+///
+/// The connection is returned as it is if the conditions are perfect.
+pub fn simulate(connection: Box<Connection>, conditions: Conditions) -> io::Result<Box<Connection>> {
+    if conditions.is_perfect() {
+        Ok(connection)
+    } else {
+        Ok(Box::new(SimulatedConnection::new(connection, conditions)?))
+    }
+}
+
+/// Insert the frame after the frames arriving at the same time or before.
+fn insert(pending: &mut VecDeque<(Instant, Option<Frame>)>, arrival: Instant, frame: Option<Frame>) {
+    let index = pending.iter().position(|&(due, _)| due > arrival).unwrap_or(pending.len());
+    pending.insert(index, (arrival, frame));
+}
+
+fn closed_error() -> codec::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed").into()
+}
+
+impl Connection for SimulatedConnection {
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), codec::Error> {
+        self.events.send(Event::Frame(Instant::now(), frame.clone())).map_err(|_| closed_error())
+    }
+
+    fn receive(&mut self) -> Result<Message, codec::Error> {
+        self.inner.receive()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(SimulatedConnection {
+            inner: self.inner.try_clone()?,
+            events: self.events.clone(),
+        }))
+    }
+
+    /// The connection is closed once the frames sent before have arrived.
+    fn shutdown(&self) {
+        let _ = self.events.send(Event::Shutdown);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use rand::{XorShiftRng, SeedableRng};
+
+    use super::{Conditions, Scheduler, SimulatedConnection};
+    use network::Message;
+    use transport::{Connection, pair};
+
+    fn scheduler(conditions: Conditions) -> Scheduler<XorShiftRng> {
+        Scheduler::new(conditions, XorShiftRng::from_seed([1, 2, 3, 4]))
+    }
+
+    #[test]
+    fn test_perfect_conditions() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(Conditions::default());
+        assert_eq!(scheduler.arrivals(now, true), vec![now]);
+        assert_eq!(scheduler.arrivals(now, false), vec![now]);
+    }
+
+    #[test]
+    fn test_reliable_frames_stay_in_order() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(Conditions {
+            latency_ms: 50,
+            jitter_ms: 30,
+            loss: 0.3,
+            duplication: 1.0,
+            reordering: 1.0,
+        });
+        let mut last = now;
+        for i in 0..100 {
+            let arrivals = scheduler.arrivals(now + Duration::from_millis(i), true);
+            assert_eq!(arrivals.len(), 1);
+            assert!(arrivals[0] >= last);
+            assert!(arrivals[0] >= now + Duration::from_millis(50));
+            last = arrivals[0];
+        }
+    }
+
+    #[test]
+    fn test_unreliable_frames() {
+        let now = Instant::now();
+        let mut lossy = scheduler(Conditions { loss: 1.0, ..Conditions::default() });
+        assert_eq!(lossy.arrivals(now, false), vec![]);
+
+        let mut duplicating = scheduler(Conditions { latency_ms: 10, duplication: 1.0, ..Conditions::default() });
+        let arrival = now + Duration::from_millis(10);
+        assert_eq!(duplicating.arrivals(now, false), vec![arrival, arrival]);
+
+        let mut reordering = scheduler(Conditions { reordering: 1.0, ..Conditions::default() });
+        assert!(reordering.arrivals(now, false)[0] > now);
+    }
+
+    #[test]
+    fn test_simulated_connection() {
+        let (a, mut b) = pair();
+        let conditions = Conditions { latency_ms: 20, ..Conditions::default() };
+        let mut a = SimulatedConnection::new(Box::new(a), conditions).unwrap();
+        let start = Instant::now();
+        a.send(&Message::GameOver(vec![])).unwrap();
+        a.shutdown();
+
+        assert_eq!(b.receive().unwrap(), Message::GameOver(vec![]));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(b.receive().is_err());
+    }
+}
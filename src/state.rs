@@ -5,7 +5,10 @@
 use std::convert::Into;
 use std::fmt;
 use std::collections::HashMap;
+use std::f64::consts::PI;
 
+use shapes::Shape;
+use settings::GameSettings;
 
 /// A unit identifier.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash)]
@@ -23,8 +26,24 @@ impl fmt::Display for UnitId {
     }
 }
 
+/// A building identifier.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash)]
+pub struct BuildingId(pub u32);
+
+impl Into<BuildingId> for u32 {
+    fn into(self) -> BuildingId {
+        BuildingId(self)
+    }
+}
+
+impl fmt::Display for BuildingId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt(f)
+    }
+}
+
 /// A client/player identifier.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash)]
 pub struct ClientId(pub u32);
 
 impl Into<ClientId> for u32 {
@@ -40,6 +59,167 @@ impl fmt::Display for ClientId {
 }
 
 
+/// The faction of a player.
+///
+/// Factions work like rock-paper-scissors: every faction beats exactly one
+/// other faction and is beaten by the remaining one.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash)]
+pub enum Faction {
+    Rock,
+    Paper,
+    Scissors,
+}
+
+/// All factions in the order they are assigned to joining players.
+///
+/// The order is chosen so that the first player beats the second, the second
+/// beats the third and the third beats the first (`A > B > C > A`).
+pub const FACTIONS: [Faction; 3] = [Faction::Rock, Faction::Scissors, Faction::Paper];
+
+impl Faction {
+    /// Return the faction for the player that joins as the `n`th player.
+    pub fn for_player(n: usize) -> Faction {
+        FACTIONS[n % FACTIONS.len()]
+    }
+
+    /// Return the position of this faction in `FACTIONS`.
+    pub fn index(&self) -> usize {
+        FACTIONS.iter().position(|f| f == self).unwrap()
+    }
+
+    /// Return whether this faction has an advantage against `other`.
+    pub fn beats(&self, other: Faction) -> bool {
+        match (*self, other) {
+            (Faction::Rock, Faction::Scissors) => true,
+            (Faction::Scissors, Faction::Paper) => true,
+            (Faction::Paper, Faction::Rock) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Damage multipliers of the rock-paper-scissors advantage matrix.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub struct DamageModel {
+    /// Multiplier for damage dealt to the faction the attacker beats
+    pub advantage: f64,
+    /// Multiplier for damage dealt to the faction that beats the attacker
+    pub disadvantage: f64,
+}
+
+impl DamageModel {
+    pub fn new(advantage: f64, disadvantage: f64) -> DamageModel {
+        DamageModel {
+            advantage: advantage,
+            disadvantage: disadvantage,
+        }
+    }
+
+    /// Return the multiplier for damage dealt by `attacker` to `defender`.
+    pub fn multiplier(&self, attacker: Faction, defender: Faction) -> f64 {
+        if attacker.beats(defender) {
+            self.advantage
+        } else if defender.beats(attacker) {
+            self.disadvantage
+        } else {
+            1.0
+        }
+    }
+
+    /// Return the effective damage dealt by `attacker` to `defender`.
+    pub fn damage(&self, base: u64, attacker: Faction, defender: Faction) -> u64 {
+        (base as f64 * self.multiplier(attacker, defender)).round() as u64
+    }
+}
+
+impl Default for DamageModel {
+    fn default() -> DamageModel {
+        DamageModel::new(1.5, 0.5)
+    }
+}
+
+
+/// Attack capabilities of a unit.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub struct Weapon {
+    /// Maximum distance to the target in m
+    pub range: f64,
+
+    /// Base damage per attack, before the `DamageModel` is applied
+    pub damage: u64,
+
+    /// Time between two attacks in ms
+    pub cooldown: f64,
+
+    /// Speed of the fired projectiles in m per ms
+    pub projectile_speed: f64,
+}
+
+impl Default for Weapon {
+    fn default() -> Weapon {
+        Weapon {
+            range: 150.0,
+            damage: 10_0000,
+            cooldown: 1000.0,
+            projectile_speed: 0.3,
+        }
+    }
+}
+
+
+/// A projectile fired by a unit.
+///
+/// Projectiles fly in a straight line until they either hit an enemy unit or
+/// their lifetime runs out.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Projectile {
+    /// The player whose unit fired the projectile
+    pub owner: ClientId,
+
+    /// X/Y position in the world in m
+    pub position: [f64; 2],
+
+    /// Direction and speed of the movement in m per ms
+    pub velocity: [f64; 2],
+
+    /// Remaining time in ms until the projectile vanishes
+    pub lifetime: f64,
+
+    /// Base damage on impact, before the `DamageModel` is applied
+    pub damage: u64,
+}
+
+impl Projectile {
+    /// Fire a projectile from `position` towards `target` with the specified weapon.
+    ///
+    /// The lifetime is chosen so that the projectile vanishes after it has
+    /// flown the range of the weapon.
+    pub fn fire(owner: ClientId, weapon: &Weapon, position: [f64; 2], target: [f64; 2]) -> Projectile {
+        let dx = target[0] - position[0];
+        let dy = target[1] - position[1];
+        let distance = (dx * dx + dy * dy).sqrt();
+        let velocity = if distance > 0.0 {
+            [dx / distance * weapon.projectile_speed, dy / distance * weapon.projectile_speed]
+        } else {
+            [0.0, 0.0]
+        };
+        Projectile {
+            owner: owner,
+            position: position,
+            velocity: velocity,
+            lifetime: weapon.range / weapon.projectile_speed,
+            damage: weapon.damage,
+        }
+    }
+
+    pub fn update(&mut self, dt_ms: f64) {
+        self.position[0] += self.velocity[0] * dt_ms;
+        self.position[1] += self.velocity[1] * dt_ms;
+        self.lifetime -= dt_ms;
+    }
+}
+
+
 /// The state of a single unit
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Unit {
@@ -58,39 +238,208 @@ pub struct Unit {
 
     /// Health of the unit
     pub health: u64,
+
+    /// Weapon of the unit
+    pub weapon: Weapon,
+
+    /// Time in ms until the unit can attack again
+    pub reload: f64,
 }
 
 impl Unit {
-    pub fn new<T: Into<UnitId>>(id: T, position: [f64; 2]) -> Unit {
+    pub fn new<T: Into<UnitId>>(id: T, position: [f64; 2], health: u64) -> Unit {
         println!("Create unit at {:?}", position);
         Unit {
             id: id.into(),
             position: position,
             angle: 0.0f64,
             speed_vector: [0.0f64, 0.0f64],
-            health: 100_0000,
+            health: health,
+            weapon: Weapon::default(),
+            reload: 0.0,
         }
     }
 
     pub fn update(&mut self, dt_ms: f64) {
         self.position[0] += self.speed_vector[0] * dt_ms;
         self.position[1] += self.speed_vector[1] * dt_ms;
+        self.reload = (self.reload - dt_ms).max(0.0);
+    }
+
+    /// Turn the unit towards the target.
+    pub fn face(&mut self, target: [f64; 2]) {
+        let dx = target[0] - self.position[0];
+        let dy = target[1] - self.position[1];
+        if dx.is_sign_negative() {
+            self.angle = (dy / dx).atan() + PI;
+        } else {
+            self.angle = (dy / dx).atan();
+        }
+    }
+
+    /// Move the unit towards the target, slowing down as it gets closer.
+    ///
+    /// `speed` is the fraction of the distance the unit moves per ms.
+    pub fn steer(&mut self, target: [f64; 2], speed: f64) {
+        self.speed_vector = [(target[0] - self.position[0]) * speed, (target[1] - self.position[1]) * speed];
+    }
+
+    /// Return the distance between the centers of this unit and `position`.
+    pub fn distance_to(&self, position: [f64; 2]) -> f64 {
+        let dx = position[0] - self.position[0];
+        let dy = position[1] - self.position[1];
+        (dx * dx + dy * dy).sqrt()
     }
 }
 
 
-/// A player has an ID and consists of 0..N `Unit`s
+/// A building consists of two merged units.
+///
+/// Buildings cannot attack, but they take less damage than units. They can be
+/// split into the original units again.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Building {
+    /// The building identifier
+    pub id: BuildingId,
+
+    /// X/Y position in the world in m
+    pub position: [f64; 2],
+
+    /// Angle of the building in radiant
+    pub angle: f64,
+
+    /// Health of the building
+    pub health: u64,
+
+    /// Multiplier for the damage the building takes
+    pub defense: f64,
+
+    /// The units the building was merged from
+    pub units: Vec<Unit>,
+}
+
+impl Building {
+    /// Merge the units into a building located at their center.
+    pub fn new<T: Into<BuildingId>>(id: T, units: Vec<Unit>) -> Building {
+        let count = units.len() as f64;
+        let position = [
+            units.iter().map(|unit| unit.position[0]).sum::<f64>() / count,
+            units.iter().map(|unit| unit.position[1]).sum::<f64>() / count,
+        ];
+        Building {
+            id: id.into(),
+            position: position,
+            angle: 0.0,
+            health: units.iter().map(|unit| unit.health).sum(),
+            defense: 0.5,
+            units: units,
+        }
+    }
+
+    /// Split the building into the original units.
+    ///
+    /// The units are placed next to each other at the position of the
+    /// building and share the damage the building has taken.
+    pub fn split(self, unit_size: f64) -> Vec<Unit> {
+        let max_health: u64 = self.units.iter().map(|unit| unit.health).sum();
+        let ratio = self.health as f64 / max_health as f64;
+        let count = self.units.len() as f64;
+        let position = self.position;
+        self.units.into_iter().enumerate().map(|(i, mut unit)| {
+            let offset = (i as f64 - (count - 1.0) / 2.0) * unit_size;
+            unit.position = [position[0] + offset, position[1]];
+            unit.speed_vector = [0.0, 0.0];
+            unit.health = ((unit.health as f64 * ratio) as u64).max(1);
+            unit.reload = 0.0;
+            unit
+        }).collect()
+    }
+}
+
+
+/// Whether a player is connected to the server.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum PlayerStatus {
+    Connected,
+    /// The connection was lost, the player may still reconnect
+    Disconnected,
+    /// The player did not reconnect in time, the server decides about the units
+    Abandoned,
+}
+
+/// A player has an ID, a `Faction` and consists of 0..N `Unit`s and `Building`s
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Player {
     pub id: ClientId,
+    pub faction: Faction,
     pub units: Vec<Unit>,
+    pub buildings: Vec<Building>,
+    /// Whether the player has lost all units and buildings
+    pub eliminated: bool,
+    /// Sequence number of the last command of the player processed by the server
+    pub last_command: u64,
+    pub status: PlayerStatus,
 }
 
 impl Player {
-    pub fn new<T: Into<ClientId>>(id: T) -> Player {
+    pub fn new<T: Into<ClientId>>(id: T, faction: Faction) -> Player {
         Player {
             id: id.into(),
+            faction: faction,
             units: vec![],
+            buildings: vec![],
+            eliminated: false,
+            last_command: 0,
+            status: PlayerStatus::Connected,
+        }
+    }
+
+    /// Return whether the player has neither units nor buildings left.
+    pub fn is_defeated(&self) -> bool {
+        self.units.is_empty() && self.buildings.is_empty()
+    }
+
+    /// Return the unit with the specified ID, if it belongs to this player.
+    pub fn unit(&self, id: UnitId) -> Option<&Unit> {
+        self.units.iter().find(|unit| unit.id == id)
+    }
+
+    /// Return a mutable reference to the unit with the specified ID, if it
+    /// belongs to this player.
+    pub fn unit_mut(&mut self, id: UnitId) -> Option<&mut Unit> {
+        self.units.iter_mut().find(|unit| unit.id == id)
+    }
+
+    /// Return the building with the specified ID, if it belongs to this player.
+    pub fn building(&self, id: BuildingId) -> Option<&Building> {
+        self.buildings.iter().find(|building| building.id == id)
+    }
+
+    /// Merge the two units into a new building with the specified ID.
+    ///
+    /// Returns `false` if one of the units does not belong to this player.
+    pub fn merge(&mut self, a: UnitId, b: UnitId, building_id: BuildingId) -> bool {
+        if a == b || self.unit(a).is_none() || self.unit(b).is_none() {
+            return false;
+        }
+        let (merged, units): (Vec<Unit>, Vec<Unit>) = self.units.drain(..)
+            .partition(|unit| unit.id == a || unit.id == b);
+        self.units = units;
+        self.buildings.push(Building::new(building_id, merged));
+        true
+    }
+
+    /// Split the building back into its units.
+    ///
+    /// Returns `false` if the building does not belong to this player.
+    pub fn split(&mut self, id: BuildingId, unit_size: f64) -> bool {
+        match self.buildings.iter().position(|building| building.id == id) {
+            Some(index) => {
+                let building = self.buildings.remove(index);
+                self.units.extend(building.split(unit_size));
+                true
+            }
+            None => false,
         }
     }
 }
@@ -103,19 +452,45 @@ impl Player {
 pub struct GameState {
     /// List of players
     pub players: Vec<Player>,
+
+    /// Projectiles that are currently in flight
+    pub projectiles: Vec<Projectile>,
+
+    /// Damage multipliers between the factions
+    pub damage_model: DamageModel,
 }
 
 impl GameState {
     pub fn new() -> GameState {
-        GameState{ players: vec![] }
+        GameState{ players: vec![], projectiles: vec![], damage_model: DamageModel::default() }
+    }
+
+    /// Return the player with the specified ID.
+    pub fn player(&self, id: ClientId) -> Option<&Player> {
+        self.players.iter().find(|player| player.id == id)
+    }
+
+    /// Return a mutable reference to the player with the specified ID.
+    pub fn player_mut(&mut self, id: ClientId) -> Option<&mut Player> {
+        self.players.iter_mut().find(|player| player.id == id)
+    }
+
+    /// Return the damage a unit of player `attacker` deals to a unit of
+    /// player `defender` with the specified base damage.
+    ///
+    /// Unknown players neither have an advantage nor a disadvantage.
+    pub fn damage(&self, base: u64, attacker: ClientId, defender: ClientId) -> u64 {
+        match (self.player(attacker), self.player(defender)) {
+            (Some(a), Some(d)) => self.damage_model.damage(base, a.faction, d.faction),
+            _ => base,
+        }
     }
 
-    pub fn update_targets(&mut self, unit_targets: &HashMap<UnitId, [f64; 2]>) {
+    pub fn update_targets(&mut self, unit_targets: &HashMap<UnitId, [f64; 2]>, settings: &GameSettings) {
         for player in self.players.iter_mut() {
             for unit in player.units.iter_mut() {
                 if let Some(target) = unit_targets.get(&unit.id) {
-                    let speed = 0.0001;
-                    unit.speed_vector = [(target[0]-unit.position[0])*speed, (target[1]-unit.position[1])*speed];
+                    unit.steer(*target, settings.unit_speed);
                 } else {
                     unit.speed_vector = [0.0,0.0];
                 }
@@ -123,19 +498,163 @@ impl GameState {
         }
     }
 
-    pub fn update(&mut self, dt: f64) {
+    /// Mark all defeated players as eliminated.
+    ///
+    /// Returns the IDs of the players that have been eliminated by this call.
+    pub fn eliminate(&mut self) -> Vec<ClientId> {
+        let mut eliminated = vec![];
+        for player in self.players.iter_mut() {
+            if !player.eliminated && player.is_defeated() {
+                player.eliminated = true;
+                eliminated.push(player.id);
+            }
+        }
+        eliminated
+    }
+
+    /// Return the IDs of the players that have not been eliminated.
+    pub fn remaining(&self) -> Vec<ClientId> {
+        self.players.iter().filter(|player| !player.eliminated).map(|player| player.id).collect()
+    }
+
+    /// Return whether the match is over.
+    ///
+    /// This is the case as soon as at most one of at least two players remains.
+    pub fn is_finished(&self) -> bool {
+        self.players.len() >= 2 && self.remaining().len() <= 1
+    }
+
+    /// Return the unit with the specified ID.
+    pub fn unit(&self, id: UnitId) -> Option<&Unit> {
+        self.players.iter().flat_map(|player| player.units.iter()).find(|unit| unit.id == id)
+    }
+
+    /// Return a mutable reference to the unit with the specified ID.
+    pub fn unit_mut(&mut self, id: UnitId) -> Option<&mut Unit> {
+        self.players.iter_mut().flat_map(|player| player.units.iter_mut()).find(|unit| unit.id == id)
+    }
+
+    /// Return the building with the specified ID.
+    pub fn building(&self, id: BuildingId) -> Option<&Building> {
+        self.players.iter().flat_map(|player| player.buildings.iter()).find(|building| building.id == id)
+    }
+
+    pub fn update(&mut self, dt: f64, settings: &GameSettings) {
         for player in self.players.iter_mut() {
             for unit in player.units.iter_mut() {
                 unit.update(dt);
             }
         }
+        for projectile in self.projectiles.iter_mut() {
+            projectile.update(dt);
+        }
+        self.impact(settings.unit_size);
+        self.attack();
+    }
+
+    /// Apply the damage of projectiles that hit an enemy unit or building and
+    /// remove the destroyed units and buildings as well as spent projectiles.
+    fn impact(&mut self, unit_size: f64) {
+        // Hits as (attacker, defender, target, base damage)
+        let mut hits = vec![];
+        let mut projectiles = Vec::with_capacity(self.projectiles.len());
+        for projectile in self.projectiles.drain(..) {
+            if projectile.lifetime <= 0.0 {
+                continue;
+            }
+            let mut target = None;
+            for player in self.players.iter().filter(|player| player.id != projectile.owner) {
+                if let Some(unit) = player.units.iter().find(|unit| unit.is_hit(unit_size, projectile.position)) {
+                    target = Some((player.id, Target::Unit(unit.id)));
+                } else if let Some(building) = player.buildings.iter()
+                    .find(|building| building.is_hit(unit_size, projectile.position)) {
+                    target = Some((player.id, Target::Building(building.id)));
+                }
+                if target.is_some() {
+                    break;
+                }
+            }
+            match target {
+                Some((defender, target)) => hits.push((projectile.owner, defender, target, projectile.damage)),
+                None => projectiles.push(projectile),
+            }
+        }
+        self.projectiles = projectiles;
+
+        for &(attacker, defender, target, base) in hits.iter() {
+            let damage = self.damage(base, attacker, defender);
+            match target {
+                Target::Unit(id) => {
+                    if let Some(unit) = self.unit_mut(id) {
+                        unit.health = unit.health.saturating_sub(damage);
+                    }
+                }
+                Target::Building(id) => {
+                    let building = self.players.iter_mut()
+                        .flat_map(|player| player.buildings.iter_mut())
+                        .find(|building| building.id == id);
+                    if let Some(building) = building {
+                        let damage = (damage as f64 * building.defense).round() as u64;
+                        building.health = building.health.saturating_sub(damage);
+                    }
+                }
+            }
+        }
+
+        for player in self.players.iter_mut() {
+            player.units.retain(|unit| unit.health > 0);
+            player.buildings.retain(|building| building.health > 0);
+        }
+    }
+
+    /// Let every reloaded unit fire a projectile at the nearest enemy unit or
+    /// building in range.
+    fn attack(&mut self) {
+        // Positions of all targets, so that they can be searched while units are mutated
+        let positions: Vec<(ClientId, [f64; 2])> = self.players.iter()
+            .flat_map(|player| {
+                player.units.iter().map(|unit| unit.position)
+                    .chain(player.buildings.iter().map(|building| building.position))
+                    .map(move |position| (player.id, position))
+            })
+            .collect();
+
+        for player in self.players.iter_mut() {
+            for unit in player.units.iter_mut() {
+                if unit.reload > 0.0 {
+                    continue;
+                }
+                let mut nearest: Option<([f64; 2], f64)> = None;
+                for &(owner, position) in positions.iter() {
+                    let distance = unit.distance_to(position);
+                    if owner == player.id || distance > unit.weapon.range {
+                        continue;
+                    }
+                    if nearest.map_or(true, |(_, d)| distance < d) {
+                        nearest = Some((position, distance));
+                    }
+                }
+                if let Some((target, _)) = nearest {
+                    self.projectiles.push(Projectile::fire(player.id, &unit.weapon, unit.position, target));
+                    unit.reload = unit.weapon.cooldown;
+                }
+            }
+        }
     }
 }
 
+/// Something that can be hit by a projectile.
+#[derive(Copy, Clone)]
+enum Target {
+    Unit(UnitId),
+    Building(BuildingId),
+}
+
 /// Data related to the entire world, like width and height.
 ///
 /// This needs to be transferred to the client only once, on connecting.
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
 pub struct WorldState {
     /// Width of the world in m
     pub x: f64,
@@ -151,3 +670,222 @@ impl WorldState {
         }
     }
 }
+
+impl Default for WorldState {
+    fn default() -> WorldState {
+        WorldState::new(800.0, 600.0)
+    }
+}
+
+/// Create a game with two players that own two units each, for tests.
+#[cfg(test)]
+pub fn two_players() -> GameState {
+    use settings::DEFAULT_UNIT_HEALTH;
+
+    let mut game = GameState::new();
+    for i in 0..2 {
+        let mut player = Player::new(i, Faction::for_player(i as usize));
+        player.units.push(Unit::new(2 * i, [100.0, 100.0 + 200.0 * i as f64], DEFAULT_UNIT_HEALTH));
+        player.units.push(Unit::new(2 * i + 1, [140.0, 100.0 + 200.0 * i as f64], DEFAULT_UNIT_HEALTH));
+        game.players.push(player);
+    }
+    game
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Faction, DamageModel, GameState, Player, Unit, Weapon, Projectile, Building};
+    use settings::{GameSettings, DEFAULT_UNIT_HEALTH};
+
+    #[test]
+    fn test_faction_cycle() {
+        // A > B > C > A for players in join order
+        let (a, b, c) = (Faction::for_player(0), Faction::for_player(1), Faction::for_player(2));
+        assert!(a.beats(b) && b.beats(c) && c.beats(a));
+        assert!(!b.beats(a) && !c.beats(b) && !a.beats(c));
+        assert!(!a.beats(a));
+
+        // Factions are reused for more than three players
+        assert_eq!(Faction::for_player(3), a);
+        assert_eq!(Faction::for_player(3).index(), 0);
+    }
+
+    #[test]
+    fn test_damage_model() {
+        let model = DamageModel::new(2.0, 0.25);
+        assert_eq!(model.damage(100, Faction::Rock, Faction::Scissors), 200);
+        assert_eq!(model.damage(100, Faction::Scissors, Faction::Rock), 25);
+        assert_eq!(model.damage(100, Faction::Paper, Faction::Paper), 100);
+    }
+
+    #[test]
+    fn test_game_state_damage() {
+        let mut game = GameState::new();
+        game.damage_model = DamageModel::new(1.5, 0.5);
+        game.players.push(Player::new(0, Faction::Rock));
+        game.players.push(Player::new(1, Faction::Scissors));
+
+        assert_eq!(game.damage(100, 0.into(), 1.into()), 150);
+        assert_eq!(game.damage(100, 1.into(), 0.into()), 50);
+        assert_eq!(game.damage(100, 0.into(), 42.into()), 100);
+    }
+
+    fn duel(distance: f64) -> GameState {
+        let mut game = GameState::new();
+        game.damage_model = DamageModel::new(1.5, 0.5);
+        let mut rock = Player::new(0, Faction::Rock);
+        rock.units.push(Unit::new(0, [100.0, 100.0], DEFAULT_UNIT_HEALTH));
+        rock.units.push(Unit::new(1, [100.0, 100.0 + distance], DEFAULT_UNIT_HEALTH));
+        let mut scissors = Player::new(1, Faction::Scissors);
+        scissors.units.push(Unit::new(2, [100.0 + distance, 100.0], DEFAULT_UNIT_HEALTH));
+        game.players.push(rock);
+        game.players.push(scissors);
+        game
+    }
+
+    /// Step the game until all projectiles have hit or vanished.
+    fn settle(game: &mut GameState) {
+        let settings = GameSettings::default();
+        game.update(1.0, &settings);
+        while !game.projectiles.is_empty() {
+            game.update(1.0, &settings);
+        }
+    }
+
+    #[test]
+    fn test_fire_at_nearest_enemy() {
+        let mut game = duel(100.0);
+        let settings = GameSettings::default();
+        game.update(1.0, &settings);
+
+        // Both rock units fire at the only enemy, which fires back at the nearest one
+        assert_eq!(game.projectiles.len(), 3);
+        let fired_back: Vec<&Projectile> = game.projectiles.iter()
+            .filter(|projectile| projectile.owner == 1.into())
+            .collect();
+        assert_eq!(fired_back.len(), 1);
+        assert!(fired_back[0].velocity[0] < 0.0);
+        assert_eq!(fired_back[0].velocity[1], 0.0);
+    }
+
+    #[test]
+    fn test_projectile_impact() {
+        let mut game = duel(100.0);
+        let base = game.unit(0.into()).unwrap().weapon.damage;
+        let health = game.unit(0.into()).unwrap().health;
+        settle(&mut game);
+
+        // Damage is applied according to the advantage matrix
+        assert_eq!(game.unit(2.into()).unwrap().health, health - 2 * base * 3 / 2);
+        assert_eq!(game.unit(0.into()).unwrap().health, health - base / 2);
+        assert_eq!(game.unit(1.into()).unwrap().health, health);
+    }
+
+    #[test]
+    fn test_attack_cooldown() {
+        let mut game = duel(100.0);
+        let settings = GameSettings::default();
+        let cooldown = game.unit(0.into()).unwrap().weapon.cooldown;
+        game.update(1.0, &settings);
+        assert_eq!(game.projectiles.len(), 3);
+
+        game.projectiles.clear();
+        game.update(cooldown / 2.0, &settings);
+        assert!(game.projectiles.is_empty());
+        game.update(cooldown / 2.0, &settings);
+        assert_eq!(game.projectiles.len(), 3);
+    }
+
+    #[test]
+    fn test_attack_out_of_range() {
+        let mut game = duel(1000.0);
+        let settings = GameSettings::default();
+        game.update(1.0, &settings);
+        assert!(game.projectiles.is_empty());
+    }
+
+    #[test]
+    fn test_projectile_lifetime() {
+        let mut game = GameState::new();
+        let settings = GameSettings::default();
+        let weapon = Weapon::default();
+        game.projectiles.push(Projectile::fire(0.into(), &weapon, [0.0, 0.0], [1.0, 0.0]));
+        game.update(weapon.range / weapon.projectile_speed - 1.0, &settings);
+        assert_eq!(game.projectiles.len(), 1);
+        game.update(1.0, &settings);
+        assert!(game.projectiles.is_empty());
+    }
+
+    #[test]
+    fn test_destroyed_units_are_removed() {
+        let mut game = duel(100.0);
+        game.unit_mut(2.into()).unwrap().health = 1;
+        settle(&mut game);
+        assert!(game.unit(2.into()).is_none());
+        assert!(game.players[1].units.is_empty());
+    }
+
+    #[test]
+    fn test_merge_and_split() {
+        let mut player = Player::new(0, Faction::Rock);
+        player.units.push(Unit::new(0, [100.0, 100.0], DEFAULT_UNIT_HEALTH));
+        player.units.push(Unit::new(1, [100.0, 150.0], DEFAULT_UNIT_HEALTH));
+        player.units.push(Unit::new(2, [300.0, 300.0], DEFAULT_UNIT_HEALTH));
+        let health = player.units[0].health;
+
+        assert!(!player.merge(0.into(), 0.into(), 0.into()));
+        assert!(!player.merge(0.into(), 42.into(), 0.into()));
+        assert!(player.merge(0.into(), 1.into(), 0.into()));
+        assert_eq!(player.units.len(), 1);
+        assert_eq!(player.buildings.len(), 1);
+        assert_eq!(player.buildings[0].position, [100.0, 125.0]);
+        assert_eq!(player.buildings[0].health, 2 * health);
+
+        // The units share the damage taken by the building
+        player.buildings[0].health = health;
+        assert!(!player.split(42.into(), 50.0));
+        assert!(player.split(0.into(), 50.0));
+        assert!(player.buildings.is_empty());
+        assert_eq!(player.unit(0.into()).unwrap().health, health / 2);
+        assert_eq!(player.unit(1.into()).unwrap().health, health / 2);
+    }
+
+    #[test]
+    fn test_building_defense() {
+        let mut game = duel(100.0);
+        let base = game.unit(2.into()).unwrap().weapon.damage;
+        let unit = game.players[0].units.remove(0);
+        let building = Building::new(0, vec![unit]);
+        let health = building.health;
+        let defense = building.defense;
+        game.players[0].buildings.push(building);
+        game.players[0].units.clear();
+        settle(&mut game);
+
+        // Buildings don't fire, but get hit with reduced damage
+        let damage = (base as f64 * 0.5 * defense).round() as u64;
+        assert_eq!(game.players[0].buildings[0].health, health - damage);
+        assert_eq!(game.unit(2.into()).unwrap().health, DEFAULT_UNIT_HEALTH);
+    }
+
+    #[test]
+    fn test_elimination() {
+        let mut game = duel(1000.0);
+        assert!(game.eliminate().is_empty());
+        assert!(!game.is_finished());
+
+        game.players[1].units.clear();
+        assert_eq!(game.eliminate(), vec![1.into()]);
+        assert!(game.eliminate().is_empty());
+        assert_eq!(game.remaining(), vec![0.into()]);
+        assert!(game.is_finished());
+    }
+
+    #[test]
+    fn test_single_player_never_finishes() {
+        let mut game = GameState::new();
+        game.players.push(Player::new(0, Faction::Rock));
+        assert_eq!(game.eliminate(), vec![0.into()]);
+        assert!(!game.is_finished());
+    }
+}
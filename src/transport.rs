@@ -0,0 +1,346 @@
+//! Connections over the transports the server and the clients support.
+//!
+//! The server and the clients only talk to the `Connection` and `Listener`
+//! traits. Besides TCP and UDP, connections can be created in memory with
+//! `pair`, so that both sides of the protocol can be tested without sockets.
+
+use std::io::{self, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use codec;
+use network::Message;
+use udp;
+
+/// Time in ms a client tries to connect before giving up
+pub const CONNECT_TIMEOUT_MS: u64 = 5000;
+
+/// The transports a connection can use.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// Reliable and ordered delivery of every message
+    Tcp,
+    /// Reliable delivery of commands, latest-wins delivery of snapshots
+    Udp,
+}
+
+/// An encoded message, ready to be sent to any number of peers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// The frame as returned by `codec::encode`
+    pub data: Arc<Vec<u8>>,
+    /// Whether the message must be delivered
+    pub reliable: bool,
+}
+
+impl Frame {
+    pub fn new(message: &Message) -> Result<Frame, codec::Error> {
+        Ok(Frame {
+            data: Arc::new(codec::encode(message)?),
+            reliable: message.is_reliable(),
+        })
+    }
+}
+
+/// A connection to a peer.
+pub trait Connection: Send {
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), codec::Error>;
+
+    /// Block until the next message has been received.
+    fn receive(&mut self) -> Result<Message, codec::Error>;
+
+    /// Return another handle to the same connection, e.g. for another thread.
+    fn try_clone(&self) -> io::Result<Box<Connection>>;
+
+    /// Close the connection, which also ends blocking `receive` calls.
+    fn shutdown(&self);
+
+    /// Let `receive` fail with a timeout error once no message arrived for
+    /// the given time, or block forever with `None`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn send(&mut self, message: &Message) -> Result<(), codec::Error> {
+        self.send_frame(&Frame::new(message)?)
+    }
+}
+
+/// Accepts the connections of clients.
+pub trait Listener {
+    /// Return the connection of a new client, without blocking.
+    fn accept(&self) -> io::Result<Option<Box<Connection>>>;
+}
+
+/// Connect to the server at `addr`, giving up after `CONNECT_TIMEOUT_MS`.
+///
+/// Over TCP, every address `addr` resolves to is tried in turn.
+pub fn connect<A: ToSocketAddrs>(transport: Transport, addr: A) -> io::Result<Box<Connection>> {
+    match transport {
+        Transport::Tcp => {
+            let timeout = Duration::from_millis(CONNECT_TIMEOUT_MS);
+            let mut error = io::Error::new(ErrorKind::InvalidInput, "No address to connect to");
+            for addr in addr.to_socket_addrs()? {
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(stream) => return Ok(Box::new(stream)),
+                    Err(e) => error = e,
+                }
+            }
+            Err(error)
+        }
+        Transport::Udp => Ok(Box::new(udp::Connection::connect(addr)?)),
+    }
+}
+
+/// Accept the connections of clients on `addr`.
+pub fn bind<A: ToSocketAddrs>(transport: Transport, addr: A) -> io::Result<Box<Listener>> {
+    match transport {
+        Transport::Tcp => {
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            Ok(Box::new(listener))
+        }
+        Transport::Udp => Ok(Box::new(udp::Listener::bind(addr)?)),
+    }
+}
+
+impl Connection for TcpStream {
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), codec::Error> {
+        Ok(self.write_all(&frame.data)?)
+    }
+
+    fn receive(&mut self) -> Result<Message, codec::Error> {
+        codec::read_message(self)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Option<Box<Connection>>> {
+        match TcpListener::accept(self) {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some(Box::new(stream)))
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Connection for udp::Connection {
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), codec::Error> {
+        udp::Connection::send_frame(self, &frame.data, frame.reliable)
+    }
+
+    fn receive(&mut self) -> Result<Message, codec::Error> {
+        udp::Connection::receive(self)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn shutdown(&self) {
+        udp::Connection::shutdown(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        udp::Connection::set_read_timeout(self, timeout);
+        Ok(())
+    }
+}
+
+impl Listener for udp::Listener {
+    fn accept(&self) -> io::Result<Option<Box<Connection>>> {
+        Ok(self.try_accept().map(|connection| Box::new(connection) as Box<Connection>))
+    }
+}
+
+/// One end of a connection within the process, see `pair`.
+///
+/// Frames are delivered reliably and in order, like over TCP. The connection
+/// is closed once every handle of one end has been dropped.
+pub struct MemoryConnection {
+    /// Frames sent by the peer, `None` once the connection is closed
+    inbox: Arc<Mutex<Receiver<Option<Frame>>>>,
+    /// Sender into the own inbox, to wake up receivers on shutdown
+    own: Sender<Option<Frame>>,
+    /// Sender into the inbox of the peer
+    peer: Sender<Option<Frame>>,
+    /// Shared by both ends
+    closed: Arc<AtomicBool>,
+    /// Time `receive` waits for a frame, shared by the handles of this end
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+    /// Number of handles of this end
+    handles: Arc<AtomicUsize>,
+}
+
+/// Create the two ends of a connection within the process.
+pub fn pair() -> (MemoryConnection, MemoryConnection) {
+    let (to_a, inbox_a) = channel();
+    let (to_b, inbox_b) = channel();
+    let closed = Arc::new(AtomicBool::new(false));
+    let a = MemoryConnection {
+        inbox: Arc::new(Mutex::new(inbox_a)),
+        own: to_a.clone(),
+        peer: to_b.clone(),
+        closed: closed.clone(),
+        read_timeout: Arc::new(Mutex::new(None)),
+        handles: Arc::new(AtomicUsize::new(1)),
+    };
+    let b = MemoryConnection {
+        inbox: Arc::new(Mutex::new(inbox_b)),
+        own: to_b,
+        peer: to_a,
+        closed: closed,
+        read_timeout: Arc::new(Mutex::new(None)),
+        handles: Arc::new(AtomicUsize::new(1)),
+    };
+    (a, b)
+}
+
+fn closed_error() -> codec::Error {
+    io::Error::new(ErrorKind::ConnectionAborted, "connection closed").into()
+}
+
+impl Clone for MemoryConnection {
+    fn clone(&self) -> MemoryConnection {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        MemoryConnection {
+            inbox: self.inbox.clone(),
+            own: self.own.clone(),
+            peer: self.peer.clone(),
+            closed: self.closed.clone(),
+            read_timeout: self.read_timeout.clone(),
+            handles: self.handles.clone(),
+        }
+    }
+}
+
+impl Drop for MemoryConnection {
+    fn drop(&mut self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shutdown();
+        }
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), codec::Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(closed_error());
+        }
+        self.peer.send(Some(frame.clone())).map_err(|_| closed_error())
+    }
+
+    fn receive(&mut self) -> Result<Message, codec::Error> {
+        let inbox = self.inbox.lock().unwrap();
+        let received = match *self.read_timeout.lock().unwrap() {
+            Some(timeout) => inbox.recv_timeout(timeout),
+            None => inbox.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Some(frame)) => codec::read_message(&mut &frame.data[..]),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(ErrorKind::TimedOut, "no frame received in time").into())
+            }
+            _ => {
+                // Keep the connection closed for other handles
+                let _ = self.own.send(None);
+                Err(closed_error())
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    /// Frames that were sent before are still received by the peer.
+    fn shutdown(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            let _ = self.own.send(None);
+            let _ = self.peer.send(None);
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use super::{Connection, Transport, connect, pair};
+    use network::{Message, ProtocolVersion};
+
+    #[test]
+    fn test_memory_connection() {
+        let (mut a, mut b) = pair();
+        let hello = Message::ClientHello(ProtocolVersion::current());
+        a.send(&hello).unwrap();
+        a.send(&Message::Ack(1)).unwrap();
+        assert_eq!(b.receive().unwrap(), hello);
+
+        // Closing one end ends both, after the pending frames
+        b.shutdown();
+        assert!(a.receive().is_err());
+        assert!(a.send(&Message::Ack(2)).is_err());
+        let mut c = b.try_clone().unwrap();
+        assert_eq!(c.receive().unwrap(), Message::Ack(1));
+        assert!(b.receive().is_err());
+        assert!(c.receive().is_err());
+    }
+
+    #[test]
+    fn test_connect_tries_every_address() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = [closed, listener.local_addr().unwrap()];
+        assert!(connect(Transport::Tcp, &addrs[..]).is_ok());
+        assert!(connect(Transport::Tcp, closed).is_err());
+    }
+
+    #[test]
+    fn test_drop() {
+        let (a, mut b) = pair();
+        let other = a.try_clone().unwrap();
+        drop(a);
+        b.send(&Message::Ack(1)).unwrap();
+
+        // Once the last handle is gone
+        drop(other);
+        assert!(b.receive().is_err());
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let (mut a, mut b) = pair();
+        a.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert!(a.receive().is_err());
+
+        // The connection stays open
+        b.send(&Message::Ack(1)).unwrap();
+        assert_eq!(a.receive().unwrap(), Message::Ack(1));
+        a.set_read_timeout(None).unwrap();
+        b.shutdown();
+        assert!(a.receive().is_err());
+    }
+}
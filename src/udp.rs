@@ -0,0 +1,660 @@
+//! Transport of messages over UDP.
+//!
+//! Every datagram is a bincode serialized `Packet`. After the client's
+//! `Connect` has been answered with `Accept`, both sides exchange `Data`
+//! packets with a sequence number and the acknowledgement of the packets
+//! received from the peer as the newest sequence number plus a bitfield of
+//! the 32 before it.
+//!
+//! Reliable messages are numbered and repeated in every packet until a packet
+//! carrying them has been acknowledged. The receiver delivers them in order.
+//! Unreliable messages are sent once and only delivered if no newer one has
+//! arrived before, which suits snapshots of the game.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bincode::{serialize, deserialize, Infinite};
+use rand;
+
+use codec::{self, Error};
+use network::{Message, IDLE_TIMEOUT_MS};
+use transport::CONNECT_TIMEOUT_MS;
+
+/// Largest payload of a UDP datagram
+pub const MAX_PACKET_SIZE: usize = 65507;
+
+/// Space reserved for the packet header and message IDs
+const HEADER_SIZE: usize = 64;
+
+/// Interval in ms in which unacknowledged reliable messages are sent again
+const RESEND_MS: u64 = 50;
+
+/// Time in ms a connection that is shut down keeps sending its reliable
+/// messages that have not been acknowledged yet
+const LINGER_MS: u64 = 1000;
+
+/// Number of packets before the newest one covered by the ack bitfield
+const ACK_WINDOW: u32 = 32;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum Packet {
+    /// Sent by the client until the server accepts the connection
+    Connect,
+    /// The server has accepted the connection
+    Accept,
+    Data(DataPacket),
+    /// The peer closes the connection
+    Disconnect,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DataPacket {
+    /// Sequence number of this packet, starting at 1
+    pub sequence: u32,
+    /// Newest sequence number received from the peer, 0 if none
+    pub ack: u32,
+    /// Bit n is set if packet `ack - 1 - n` has been received as well
+    pub ack_bits: u32,
+    /// Reliable messages that have not been acknowledged yet, with their ID
+    pub reliable: Vec<(u32, Vec<u8>)>,
+    /// Message that is dropped if a newer one has been received already
+    pub unreliable: Option<Vec<u8>>,
+}
+
+/// Sequencing and reliability of one side of a connection.
+///
+/// This does not know about sockets, packets built by one channel are passed
+/// to `receive` of the channel of the peer.
+pub struct Channel {
+    /// Sequence number of the next packet
+    sequence: u32,
+    /// Newest sequence number received from the peer
+    remote_sequence: u32,
+    /// Packets received before `remote_sequence`
+    ack_bits: u32,
+    /// Whether the peer is waiting for an acknowledgement
+    ack_pending: bool,
+    /// ID of the next reliable message
+    next_reliable: u32,
+    /// Reliable messages that have not been acknowledged yet
+    unacked: VecDeque<(u32, Vec<u8>)>,
+    /// IDs of the reliable messages carried by the recently sent packets
+    in_flight: VecDeque<(u32, Vec<u32>)>,
+    /// ID of the next reliable message to deliver
+    next_delivery: u32,
+    /// Reliable messages received ahead of `next_delivery`
+    early: BTreeMap<u32, Vec<u8>>,
+    /// Sequence number of the packet of the last delivered unreliable message
+    newest_unreliable: u32,
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel {
+            sequence: 1,
+            remote_sequence: 0,
+            ack_bits: 0,
+            ack_pending: false,
+            next_reliable: 0,
+            unacked: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            next_delivery: 0,
+            early: BTreeMap::new(),
+            newest_unreliable: 0,
+        }
+    }
+
+    /// Queue a message that is sent until the peer acknowledges it.
+    pub fn send_reliable(&mut self, payload: Vec<u8>) {
+        self.unacked.push_back((self.next_reliable, payload));
+        self.next_reliable += 1;
+    }
+
+    /// Return whether reliable messages wait for their acknowledgement.
+    pub fn has_unacked(&self) -> bool {
+        !self.unacked.is_empty()
+    }
+
+    /// Return whether a packet should be sent even without new messages.
+    pub fn needs_flush(&self) -> bool {
+        self.ack_pending || !self.unacked.is_empty()
+    }
+
+    /// Build the next packet with the unacknowledged reliable messages that
+    /// fit next to the unreliable one.
+    pub fn packet(&mut self, unreliable: Option<Vec<u8>>) -> DataPacket {
+        let mut size = HEADER_SIZE + unreliable.as_ref().map_or(0, |payload| payload.len());
+        let mut reliable = vec![];
+        for &(id, ref payload) in self.unacked.iter() {
+            size += payload.len() + 12;
+            if size > MAX_PACKET_SIZE && !reliable.is_empty() {
+                break;
+            }
+            reliable.push((id, payload.clone()));
+        }
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+        if !reliable.is_empty() {
+            self.in_flight.push_back((sequence, reliable.iter().map(|&(id, _)| id).collect()));
+            if self.in_flight.len() > ACK_WINDOW as usize + 1 {
+                self.in_flight.pop_front();
+            }
+        }
+        self.ack_pending = false;
+        DataPacket {
+            sequence: sequence,
+            ack: self.remote_sequence,
+            ack_bits: self.ack_bits,
+            reliable: reliable,
+            unreliable: unreliable,
+        }
+    }
+
+    /// Process a packet of the peer and return the messages that are ready
+    /// for delivery, in order.
+    pub fn receive(&mut self, packet: DataPacket) -> Vec<Vec<u8>> {
+        // Forget the reliable messages the peer has received
+        let (ack, ack_bits) = (packet.ack, packet.ack_bits);
+        let is_acked = |sequence: u32| {
+            sequence == ack ||
+                (sequence < ack && ack - sequence <= ACK_WINDOW && ack_bits & 1 << (ack - sequence - 1) != 0)
+        };
+        let mut received = vec![];
+        self.in_flight.retain(|&(sequence, ref ids)| {
+            if is_acked(sequence) {
+                received.extend(ids.iter().cloned());
+                false
+            } else {
+                true
+            }
+        });
+        self.unacked.retain(|&(id, _)| !received.contains(&id));
+
+        // Remember the packet for our acknowledgements
+        if packet.sequence > self.remote_sequence {
+            let shift = packet.sequence - self.remote_sequence;
+            self.ack_bits = self.ack_bits.checked_shl(shift).unwrap_or(0);
+            if self.remote_sequence > 0 && shift <= ACK_WINDOW {
+                self.ack_bits |= 1 << (shift - 1);
+            }
+            self.remote_sequence = packet.sequence;
+        } else if packet.sequence < self.remote_sequence {
+            let age = self.remote_sequence - packet.sequence;
+            if age <= ACK_WINDOW {
+                self.ack_bits |= 1 << (age - 1);
+            }
+        }
+
+        let mut messages = vec![];
+        if !packet.reliable.is_empty() {
+            self.ack_pending = true;
+        }
+        for (id, payload) in packet.reliable {
+            if id >= self.next_delivery {
+                self.early.insert(id, payload);
+            }
+        }
+        while let Some(payload) = self.early.remove(&self.next_delivery) {
+            messages.push(payload);
+            self.next_delivery += 1;
+        }
+        if let Some(payload) = packet.unreliable {
+            if packet.sequence > self.newest_unreliable {
+                self.newest_unreliable = packet.sequence;
+                messages.push(payload);
+            }
+        }
+        messages
+    }
+}
+
+/// State of a connection, shared with the thread receiving its packets.
+struct Shared {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    channel: Mutex<Channel>,
+    /// Set by `shutdown`, no more messages are sent
+    closing: AtomicBool,
+    closed: AtomicBool,
+    /// Probability that an outgoing data packet is dropped
+    loss: Mutex<f64>,
+    /// Time `receive` waits for a message, forever if `None`
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Shared {
+    fn new(socket: UdpSocket, peer: SocketAddr) -> Shared {
+        Shared {
+            socket: socket,
+            peer: peer,
+            channel: Mutex::new(Channel::new()),
+            closing: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            loss: Mutex::new(0.0),
+            read_timeout: Mutex::new(None),
+        }
+    }
+
+    fn send_packet(&self, packet: &Packet) -> io::Result<()> {
+        if let Packet::Data(_) = *packet {
+            if rand::random::<f64>() < *self.loss.lock().unwrap() {
+                return Ok(());
+            }
+        }
+        let data = serialize(packet, Infinite).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        self.socket.send_to(&data, self.peer)?;
+        Ok(())
+    }
+
+    /// Send the unacknowledged reliable messages and pending acknowledgements.
+    fn flush(&self) -> io::Result<()> {
+        let packet = {
+            let mut channel = self.channel.lock().unwrap();
+            if !channel.needs_flush() {
+                return Ok(());
+            }
+            channel.packet(None)
+        };
+        self.send_packet(&Packet::Data(packet))
+    }
+
+    /// Process a packet of the peer and pass the delivered messages on.
+    fn handle(&self, packet: Packet, messages: &Sender<Vec<u8>>) {
+        match packet {
+            Packet::Data(packet) => {
+                let delivered = self.channel.lock().unwrap().receive(packet);
+                for message in delivered {
+                    let _ = messages.send(message);
+                }
+            }
+            Packet::Connect => {
+                // Our Accept got lost
+                let _ = self.send_packet(&Packet::Accept);
+            }
+            Packet::Accept => {}
+            Packet::Disconnect => self.closed.store(true, Ordering::SeqCst),
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+fn closed_error() -> Error {
+    Error::Io(io::Error::new(ErrorKind::ConnectionAborted, "The connection is closed"))
+}
+
+/// A connection over UDP.
+///
+/// Clones refer to the same connection.
+#[derive(Clone)]
+pub struct Connection {
+    shared: Arc<Shared>,
+    incoming: Arc<Mutex<Receiver<Vec<u8>>>>,
+}
+
+impl Connection {
+    /// Connect to a server listening with a `Listener`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Connection> {
+        let peer = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "No address to connect to"))?;
+        let local = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(Duration::from_millis(RESEND_MS)))?;
+        let shared = Arc::new(Shared::new(socket, peer));
+
+        // Knock until the server answers
+        let deadline = Instant::now() + Duration::from_millis(CONNECT_TIMEOUT_MS);
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            if Instant::now() > deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "The server did not accept the connection"));
+            }
+            shared.send_packet(&Packet::Connect)?;
+            match shared.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    if from == peer && deserialize::<Packet>(&buffer[..len]).ok() == Some(Packet::Accept) {
+                        break;
+                    }
+                }
+                Err(ref e) if is_timeout(e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Receiver loop, which also sends the unacknowledged messages again
+        let (messages, incoming) = channel();
+        let receiver = shared.clone();
+        thread::spawn(move || {
+            let mut last_flush = Instant::now();
+            while !receiver.closed.load(Ordering::SeqCst) {
+                match receiver.socket.recv_from(&mut buffer) {
+                    Ok((len, from)) if from == peer => {
+                        match deserialize::<Packet>(&buffer[..len]) {
+                            Ok(packet) => receiver.handle(packet, &messages),
+                            Err(e) => println!("Invalid packet from {}: {}", from, e),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(ref e) if is_timeout(e) => {}
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        receiver.closed.store(true, Ordering::SeqCst);
+                    }
+                }
+                if last_flush.elapsed() >= Duration::from_millis(RESEND_MS) {
+                    let _ = receiver.flush();
+                    last_flush = Instant::now();
+                }
+            }
+        });
+        Ok(Connection::new(shared, incoming))
+    }
+
+    fn new(shared: Arc<Shared>, incoming: Receiver<Vec<u8>>) -> Connection {
+        Connection {
+            shared: shared,
+            incoming: Arc::new(Mutex::new(incoming)),
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.peer
+    }
+
+    /// Drop outgoing data packets with the given probability, to simulate a
+    /// lossy network.
+    pub fn set_packet_loss(&self, loss: f64) {
+        *self.shared.loss.lock().unwrap() = loss;
+    }
+
+    /// Send the message, snapshots unreliably and everything else reliably.
+    pub fn send(&self, message: &Message) -> Result<(), Error> {
+        self.send_payload(serialize(message, Infinite)?, message.is_reliable())
+    }
+
+    /// Send a frame encoded by `codec::encode`.
+    pub fn send_frame(&self, frame: &[u8], reliable: bool) -> Result<(), Error> {
+        self.send_payload(codec::payload(frame).to_vec(), reliable)
+    }
+
+    fn send_payload(&self, payload: Vec<u8>, reliable: bool) -> Result<(), Error> {
+        if self.shared.closing.load(Ordering::SeqCst) || self.shared.closed.load(Ordering::SeqCst) {
+            return Err(closed_error());
+        }
+        if payload.len() > MAX_PACKET_SIZE - HEADER_SIZE {
+            return Err(Error::FrameTooLarge(payload.len()));
+        }
+        let packet = {
+            let mut channel = self.shared.channel.lock().unwrap();
+            if reliable {
+                channel.send_reliable(payload);
+                channel.packet(None)
+            } else {
+                channel.packet(Some(payload))
+            }
+        };
+        self.shared.send_packet(&Packet::Data(packet))?;
+        Ok(())
+    }
+
+    /// Let `receive` fail once no message arrived for the given time.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.shared.read_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Block until the next message has been received.
+    pub fn receive(&self) -> Result<Message, Error> {
+        let incoming = self.incoming.lock().unwrap();
+        let payload = match *self.shared.read_timeout.lock().unwrap() {
+            Some(timeout) => incoming.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => {
+                    Error::Io(io::Error::new(ErrorKind::TimedOut, "No message received in time"))
+                }
+                RecvTimeoutError::Disconnected => closed_error(),
+            })?,
+            None => incoming.recv().map_err(|_| closed_error())?,
+        };
+        Ok(deserialize(&payload)?)
+    }
+
+    /// Close the connection, the peer is notified.
+    ///
+    /// Reliable messages sent before, like the reason for closing, are sent
+    /// again for up to `LINGER_MS` until the peer acknowledges them. Blocking
+    /// `receive` calls end once the connection is closed.
+    pub fn shutdown(&self) {
+        if self.shared.closing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            // The receiver loop sends the messages again and processes the acks
+            let deadline = Instant::now() + Duration::from_millis(LINGER_MS);
+            while shared.channel.lock().unwrap().has_unacked() && !shared.closed.load(Ordering::SeqCst) &&
+                Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(RESEND_MS));
+            }
+            if !shared.closed.swap(true, Ordering::SeqCst) {
+                let _ = shared.send_packet(&Packet::Disconnect);
+            }
+        });
+    }
+}
+
+/// Accepts the connections of clients on a UDP socket.
+///
+/// All connections share the socket, a thread dispatches the received
+/// packets to them. Connections of peers that have been silent for
+/// `IDLE_TIMEOUT_MS` are closed.
+pub struct Listener {
+    local_addr: SocketAddr,
+    accepted: Receiver<Connection>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Listener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Listener> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(Duration::from_millis(RESEND_MS)))?;
+        let local_addr = socket.local_addr()?;
+        let (accept, accepted) = channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let listener_closed = closed.clone();
+
+        // Dispatcher loop
+        thread::spawn(move || {
+            // Connection, delivery of its messages and time of its last packet
+            let mut peers: HashMap<SocketAddr, (Arc<Shared>, Sender<Vec<u8>>, Instant)> = HashMap::new();
+            let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+            let mut last_flush = Instant::now();
+            while !listener_closed.load(Ordering::SeqCst) {
+                match socket.recv_from(&mut buffer) {
+                    Ok((len, from)) => {
+                        match deserialize::<Packet>(&buffer[..len]) {
+                            Ok(Packet::Connect) if !peers.contains_key(&from) => {
+                                let shared = match socket.try_clone() {
+                                    Ok(socket) => Arc::new(Shared::new(socket, from)),
+                                    Err(e) => {
+                                        println!("Error: {:?}", e);
+                                        continue;
+                                    }
+                                };
+                                let (messages, incoming) = channel();
+                                let _ = shared.send_packet(&Packet::Accept);
+                                let _ = accept.send(Connection::new(shared.clone(), incoming));
+                                peers.insert(from, (shared, messages, Instant::now()));
+                            }
+                            Ok(packet) => {
+                                let peer = peers.get_mut(&from);
+                                if let Some(&mut (ref shared, ref messages, ref mut last_seen)) = peer {
+                                    *last_seen = Instant::now();
+                                    shared.handle(packet, messages);
+                                }
+                            }
+                            Err(e) => println!("Invalid packet from {}: {}", from, e),
+                        }
+                    }
+                    Err(ref e) if is_timeout(e) => {}
+                    Err(e) => println!("Error: {:?}", e),
+                }
+                if last_flush.elapsed() >= Duration::from_millis(RESEND_MS) {
+                    for &(ref shared, _, _) in peers.values() {
+                        let _ = shared.flush();
+                    }
+                    last_flush = Instant::now();
+                }
+                // Dropping the sender ends the connection for the reader
+                let now = Instant::now();
+                peers.retain(|from, &mut (ref shared, _, last_seen)| {
+                    if now.duration_since(last_seen) > Duration::from_millis(IDLE_TIMEOUT_MS) {
+                        println!("Peer {} timed out", from);
+                        shared.closed.store(true, Ordering::SeqCst);
+                    }
+                    !shared.closed.load(Ordering::SeqCst)
+                });
+            }
+        });
+
+        Ok(Listener {
+            local_addr: local_addr,
+            accepted: accepted,
+            closed: closed,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Return the connection of a new client, without blocking.
+    pub fn try_accept(&self) -> Option<Connection> {
+        self.accepted.try_recv().ok()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Channel, Connection, Listener};
+    use network::{Message, ErrorCode, ProtocolVersion};
+    use simulator::{self, Conditions};
+    use state::GameState;
+
+    #[test]
+    fn test_reliable_in_order_despite_loss() {
+        let mut a = Channel::new();
+        let mut b = Channel::new();
+
+        // Two of three packets of `a` and every other packet of `b` are lost
+        let mut delivered = vec![];
+        for round in 0..30u8 {
+            if round < 10 {
+                a.send_reliable(vec![round]);
+            }
+            let packet = a.packet(None);
+            if round % 3 != 2 {
+                continue;
+            }
+            delivered.extend(b.receive(packet));
+            let ack = b.packet(None);
+            if round % 2 == 0 {
+                a.receive(ack);
+            }
+        }
+        assert_eq!(delivered, (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+        assert!(!a.needs_flush());
+    }
+
+    #[test]
+    fn test_unreliable_latest_wins() {
+        let mut a = Channel::new();
+        let mut b = Channel::new();
+        let old = a.packet(Some(vec![1]));
+        let new = a.packet(Some(vec![2]));
+        assert_eq!(b.receive(new), vec![vec![2]]);
+        assert!(b.receive(old).is_empty());
+    }
+
+    #[test]
+    fn test_ack_bits() {
+        let mut a = Channel::new();
+        let mut b = Channel::new();
+        let packets: Vec<_> = (0..4).map(|_| a.packet(None)).collect();
+        for packet in packets.into_iter().filter(|packet| packet.sequence != 2) {
+            b.receive(packet);
+        }
+        let ack = b.packet(None);
+        assert_eq!(ack.ack, 4);
+        // Packets 3 and 1 have been received, 2 is missing
+        assert_eq!(ack.ack_bits, 0b101);
+    }
+
+    fn accept(listener: &Listener) -> Connection {
+        for _ in 0..100 {
+            if let Some(connection) = listener.try_accept() {
+                return connection;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("No connection accepted");
+    }
+
+    #[test]
+    fn test_loopback_with_loss() {
+        let listener = Listener::bind("127.0.0.1:0").unwrap();
+        let client = Connection::connect(listener.local_addr()).unwrap();
+        let server = accept(&listener);
+        client.set_packet_loss(0.5);
+        server.set_packet_loss(0.5);
+
+        for i in 0..5 {
+            client.send(&Message::ClientHello(ProtocolVersion { version: i, features: 0 })).unwrap();
+        }
+        for i in 0..5 {
+            assert_eq!(server.receive().unwrap(),
+                       Message::ClientHello(ProtocolVersion { version: i, features: 0 }));
+        }
+
+        server.set_packet_loss(0.0);
+        server.send(&Message::UpdateGamestate(1, GameState::new())).unwrap();
+        assert_eq!(client.receive().unwrap(), Message::UpdateGamestate(1, GameState::new()));
+
+        client.shutdown();
+        assert!(server.receive().is_err());
+    }
+
+    #[test]
+    fn test_shutdown_delivers_reliable_messages() {
+        let listener = Listener::bind("127.0.0.1:0").unwrap();
+        let client = Connection::connect(listener.local_addr()).unwrap();
+        let server = accept(&listener);
+        server.set_packet_loss(0.5);
+        let conditions = Conditions { latency_ms: 10, loss: 0.5, ..Conditions::default() };
+        let mut server = simulator::simulate(Box::new(server), conditions).unwrap();
+
+        // Like a server rejecting a client
+        let error = Message::Error(ErrorCode::ServerFull, "Full".into());
+        server.send(&error).unwrap();
+        server.shutdown();
+        assert_eq!(client.receive().unwrap(), error);
+        assert!(client.receive().is_err());
+    }
+}